@@ -15,16 +15,25 @@ fn main() {
 }
 
 fn run() -> io::Result<()> {
+    // Optional `--json` flag selects machine-readable output.
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|a| a == "--json");
+    args.retain(|a| a != "--json");
+
     // First parameter is target disk image (optional, default: fixtures sample)
     let sample = "tests/fixtures/gpt-linux-disk-01.img".to_string();
-    let input = std::env::args().nth(1).unwrap_or(sample);
+    let input = args.into_iter().next().unwrap_or(sample);
 
     // Open disk image.
     let diskpath = std::path::Path::new(&input);
-    let cfg = gpt::GptConfig::new().writable(false);
-    let disk = cfg.open(diskpath)?;
+    let cfg = gpt::GptConfig::new().writable(false).logical_block_size_auto();
+    let mut disk = cfg.open(diskpath)?;
 
     // Print GPT layout.
+    if json {
+        print!("{}", disk.to_json()?);
+        return Ok(());
+    }
     println!("Disk (primary) header: {:#?}", disk.primary_header());
     println!("Partition layout: {:#?}", disk.partitions());
 
@@ -0,0 +1,453 @@
+//! Device adapters.
+//!
+//! This module provides wrappers that can be used as a `DiskDevice`
+//! on top of another device, changing how I/O is performed on it.
+
+use crate::disk;
+use log::*;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A heap buffer whose content starts at a memory address aligned
+/// to a given power of two.
+struct AlignedBuf {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn zeroed(len: usize, align: usize) -> Self {
+        let storage = vec![0u8; len + align];
+        let misalign = storage.as_ptr() as usize % align;
+        let offset = if misalign == 0 { 0 } else { align - misalign };
+        Self { storage, offset, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+/// A device performing only full, memory-aligned logical sector I/O.
+///
+/// Every read and write on the inner device covers whole logical
+/// sectors, at sector-aligned offsets and from sector-aligned memory
+/// buffers. Sub-sector writes (e.g. MBR edits) are turned into
+/// read-modify-write cycles. This makes it possible to use devices
+/// opened with `O_DIRECT`, bypassing the page cache.
+#[derive(Debug)]
+pub struct AlignedDevice<D> {
+    inner: D,
+    lb_size: usize,
+    pos: u64,
+}
+
+impl<D: Read + Write + Seek> AlignedDevice<D> {
+    /// Wrap `inner`, performing I/O in units of `lb_size` bytes.
+    pub fn new(inner: D, lb_size: disk::LogicalBlockSize) -> Self {
+        Self { inner, lb_size: lb_size.into(), pos: 0 }
+    }
+
+    /// Return a reference to the inner device.
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return a mutable reference to the inner device.
+    ///
+    /// Direct I/O on it bypasses alignment handling.
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Unwrap this device, returning the inner one.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Return the sector-aligned byte range covering `len` bytes at
+    /// the current position, as (start offset, length).
+    fn span(&self, len: usize) -> io::Result<(u64, usize)> {
+        let lb = self.lb_size as u64;
+        let start = self.pos / lb * lb;
+        let end = self
+            .pos
+            .checked_add(len as u64)
+            .and_then(|e| e.checked_add(lb - 1))
+            .map(|e| e / lb * lb)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "aligned I/O overflow - offset"))?;
+        Ok((start, (end - start) as usize))
+    }
+
+    /// Read whole sectors at `offset` into `buf`, returning the
+    /// number of bytes available before the end of the device.
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut done = 0;
+        while done < buf.len() {
+            match self.inner.read(&mut buf[done..]) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+}
+
+impl<D: Read + Write + Seek> Read for AlignedDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, len) = self.span(buf.len())?;
+        let mut sectors = AlignedBuf::zeroed(len, self.lb_size);
+        let avail = self.read_sectors(start, sectors.as_mut_slice())?;
+        let head = (self.pos - start) as usize;
+        let n = avail.saturating_sub(head).min(buf.len());
+        buf[..n].copy_from_slice(&sectors.as_slice()[head..head + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Read + Write + Seek> Write for AlignedDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, len) = self.span(buf.len())?;
+        let lb = self.lb_size;
+        let head = (self.pos - start) as usize;
+        let tail = len - head - buf.len();
+        let mut sectors = AlignedBuf::zeroed(len, lb);
+        // Read-modify-write for partially covered first and last sectors.
+        if head > 0 {
+            trace!("read-modify-write of sector at {} bytes", start);
+            self.read_sectors(start, &mut sectors.as_mut_slice()[..lb])?;
+        }
+        if tail > 0 && (len > lb || head == 0) {
+            let last = len - lb;
+            trace!("read-modify-write of sector at {} bytes", start + last as u64);
+            self.read_sectors(start + last as u64, &mut sectors.as_mut_slice()[last..])?;
+        }
+        sectors.as_mut_slice()[head..head + buf.len()].copy_from_slice(buf);
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.inner.write_all(sectors.as_slice())?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<D: Read + Write + Seek> Seek for AlignedDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => offset_by(self.pos, d),
+            SeekFrom::End(d) => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                offset_by(len, d)
+            }
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// A byte window of another device, presented as a full device.
+///
+/// Offsets are relative to the start of the window, so that a GPT disk
+/// nested at an offset (e.g. inside a partition, or after a container
+/// header) can be opened with `GptConfig::open_from_device`. A window
+/// may extend to the end of the inner device, or have a fixed length:
+/// in that case any I/O past its end is refused, reads return EOF and
+/// writes return an error.
+#[derive(Debug)]
+pub struct OffsetDevice<D> {
+    inner: D,
+    start: u64,
+    len: Option<u64>,
+    pos: u64,
+}
+
+/// A `Read + Write + Seek` view bounded to a single partition,
+/// see `GptDisk::partition_device`.
+pub type PartitionDevice<D> = OffsetDevice<D>;
+
+impl<D: Read + Write + Seek> OffsetDevice<D> {
+    /// Create a window of `len` bytes of `inner`, starting at byte `start`.
+    pub fn new(inner: D, start: u64, len: u64) -> io::Result<Self> {
+        if start.checked_add(len).is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "offset device overflow - end"));
+        }
+        Ok(Self { inner, start, len: Some(len), pos: 0 })
+    }
+
+    /// Create a window of `inner` from byte `start` up to its end.
+    pub fn from_offset(inner: D, start: u64) -> Self {
+        Self { inner, start, len: None, pos: 0 }
+    }
+
+    /// Return the starting offset (in bytes) of this window on the inner device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Return the length (in bytes) of this window, if fixed.
+    pub fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Return whether this window has a fixed length of zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == Some(0)
+    }
+
+    /// Return a reference to the inner device.
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwrap this device, returning the inner one.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Return the absolute offset of the current position.
+    fn inner_pos(&self) -> io::Result<u64> {
+        self.start
+            .checked_add(self.pos)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "offset device overflow - position"))
+    }
+}
+
+impl<D: Read + Write + Seek> Read for OffsetDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.len {
+            Some(len) => (buf.len() as u64).min(len.saturating_sub(self.pos)) as usize,
+            None => buf.len(),
+        };
+        if n == 0 {
+            return Ok(0);
+        }
+        let offset = self.inner_pos()?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let n = self.inner.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Read + Write + Seek> Write for OffsetDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(len) = self.len {
+            if buf.len() as u64 > len.saturating_sub(self.pos) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("write of {} bytes at offset {} past end of device", buf.len(), self.pos),
+                ));
+            }
+        }
+        let offset = self.inner_pos()?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<D: Read + Write + Seek> Seek for OffsetDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => offset_by(self.pos, d),
+            SeekFrom::End(d) => {
+                let len = match self.len {
+                    Some(len) => len,
+                    None => self.inner.seek(SeekFrom::End(0))?.saturating_sub(self.start),
+                };
+                offset_by(len, d)
+            }
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// A copy-on-write overlay on top of another device.
+///
+/// Reads go through to the base device, but every write is captured in
+/// memory instead. Captured writes can then be inspected (e.g. by
+/// reopening the overlay as a GPT disk), and either discarded or
+/// committed all at once to the base device.
+#[derive(Debug)]
+pub struct OverlayDevice<D> {
+    base: D,
+    chunks: BTreeMap<u64, Vec<u8>>,
+    written_end: u64,
+    pos: u64,
+}
+
+impl<D: Read + Write + Seek> OverlayDevice<D> {
+    /// Size (in bytes) of chunks captured in memory.
+    const CHUNK_SIZE: u64 = disk::MIN_SECTOR_SIZE;
+
+    /// Create an overlay on top of `base`, with no captured writes.
+    pub fn new(base: D) -> Self {
+        Self { base, chunks: BTreeMap::new(), written_end: 0, pos: 0 }
+    }
+
+    /// Return a reference to the base device.
+    pub fn get_ref(&self) -> &D {
+        &self.base
+    }
+
+    /// Return whether any write has been captured.
+    pub fn is_modified(&self) -> bool {
+        !self.chunks.is_empty()
+    }
+
+    /// Discard all captured writes.
+    pub fn discard(&mut self) {
+        self.chunks.clear();
+        self.written_end = 0;
+    }
+
+    /// Write all captured writes to the base device, and flush it.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let end = self.base_len()?.max(self.written_end);
+        for (index, chunk) in &self.chunks {
+            let offset = index * Self::CHUNK_SIZE;
+            let len = (end - offset).min(Self::CHUNK_SIZE) as usize;
+            trace!("committing {} bytes at {}", len, offset);
+            self.base.seek(SeekFrom::Start(offset))?;
+            self.base.write_all(&chunk[..len])?;
+        }
+        self.base.flush()?;
+        self.discard();
+        Ok(())
+    }
+
+    /// Unwrap this device, returning the base one and discarding
+    /// any captured write.
+    pub fn into_inner(self) -> D {
+        self.base
+    }
+
+    fn base_len(&mut self) -> io::Result<u64> {
+        self.base.seek(SeekFrom::End(0))
+    }
+
+    /// Read a whole chunk from the base device, zero-filled past its end.
+    fn read_base_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let mut chunk = vec![0u8; Self::CHUNK_SIZE as usize];
+        self.base.seek(SeekFrom::Start(index * Self::CHUNK_SIZE))?;
+        let mut done = 0;
+        while done < chunk.len() {
+            match self.base.read(&mut chunk[done..]) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+impl<D: Read + Write + Seek> Read for OverlayDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.base_len()?.max(self.written_end);
+        let n = (buf.len() as u64).min(end.saturating_sub(self.pos)) as usize;
+        let mut done = 0;
+        while done < n {
+            let pos = self.pos + done as u64;
+            let index = pos / Self::CHUNK_SIZE;
+            let skip = (pos % Self::CHUNK_SIZE) as usize;
+            let len = (Self::CHUNK_SIZE as usize - skip).min(n - done);
+            match self.chunks.get(&index) {
+                Some(chunk) => buf[done..done + len].copy_from_slice(&chunk[skip..skip + len]),
+                None => {
+                    let chunk = self.read_base_chunk(index)?;
+                    buf[done..done + len].copy_from_slice(&chunk[skip..skip + len]);
+                }
+            }
+            done += len;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Read + Write + Seek> Write for OverlayDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = self.pos + done as u64;
+            let index = pos / Self::CHUNK_SIZE;
+            let skip = (pos % Self::CHUNK_SIZE) as usize;
+            let len = (Self::CHUNK_SIZE as usize - skip).min(buf.len() - done);
+            if !self.chunks.contains_key(&index) {
+                let chunk = self.read_base_chunk(index)?;
+                self.chunks.insert(index, chunk);
+            }
+            if let Some(chunk) = self.chunks.get_mut(&index) {
+                chunk[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+            }
+            done += len;
+        }
+        self.pos += buf.len() as u64;
+        self.written_end = self.written_end.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: Read + Write + Seek> Seek for OverlayDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => offset_by(self.pos, d),
+            SeekFrom::End(d) => {
+                let len = self.base_len()?.max(self.written_end);
+                offset_by(len, d)
+            }
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
@@ -1,58 +1,76 @@
 //! Disk-related types and helper functions.
 
 use super::{GptConfig, GptDisk};
+use crate::header;
+use log::*;
+use std::io::{Read, Seek, SeekFrom};
 use std::{convert::TryFrom, fmt, io, path};
 
 /// Default size of a logical sector (bytes).
 pub const DEFAULT_SECTOR_SIZE: LogicalBlockSize = LogicalBlockSize::Lb512;
 
+/// Smallest supported logical sector size (bytes).
+pub const MIN_SECTOR_SIZE: u64 = 512;
+
+/// Largest supported logical sector size (bytes).
+pub const MAX_SECTOR_SIZE: u64 = 65536;
+
 /// Logical block/sector size of a GPT disk.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum LogicalBlockSize {
+///
+/// Any power of two from 512 bytes to 64 KiB is a valid size; the most
+/// common ones are available as associated constants.
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct LogicalBlockSize(u64);
+
+#[allow(non_upper_case_globals)]
+impl LogicalBlockSize {
     /// 512 bytes.
-    Lb512,
+    pub const Lb512: LogicalBlockSize = LogicalBlockSize(512);
+    /// 1024 bytes.
+    pub const Lb1024: LogicalBlockSize = LogicalBlockSize(1024);
+    /// 2048 bytes.
+    pub const Lb2048: LogicalBlockSize = LogicalBlockSize(2048);
     /// 4096 bytes.
-    Lb4096,
+    pub const Lb4096: LogicalBlockSize = LogicalBlockSize(4096);
+    /// 8192 bytes.
+    pub const Lb8192: LogicalBlockSize = LogicalBlockSize(8192);
 }
 
-impl Into<u64> for LogicalBlockSize {
-    fn into(self) -> u64 {
-        match self {
-            LogicalBlockSize::Lb512 => 512,
-            LogicalBlockSize::Lb4096 => 4096,
-        }
+impl From<LogicalBlockSize> for u64 {
+    fn from(lb_size: LogicalBlockSize) -> u64 {
+        lb_size.0
     }
 }
 
-impl Into<usize> for LogicalBlockSize {
-    fn into(self) -> usize {
-        match self {
-            LogicalBlockSize::Lb512 => 512,
-            LogicalBlockSize::Lb4096 => 4096,
-        }
+impl From<LogicalBlockSize> for usize {
+    fn from(lb_size: LogicalBlockSize) -> usize {
+        // `TryFrom<u64>` already bounded the size to `MAX_SECTOR_SIZE`.
+        lb_size.0 as usize
     }
 }
 
 impl TryFrom<u64> for LogicalBlockSize {
     type Error = io::Error;
     fn try_from(v: u64) -> Result<Self, Self::Error> {
-        match v {
-            512 => Ok(LogicalBlockSize::Lb512),
-            4096 => Ok(LogicalBlockSize::Lb4096),
-            _ => Err(io::Error::new(
+        if v < MIN_SECTOR_SIZE || v > MAX_SECTOR_SIZE || !v.is_power_of_two() {
+            return Err(io::Error::new(
                 io::ErrorKind::Other,
-                "unsupported logical block size (must be 512 or 4096)"
-            )),
+                "unsupported logical block size (must be a power of two, from 512 to 65536)"
+            ));
         }
+        Ok(LogicalBlockSize(v))
+    }
+}
+
+impl fmt::Debug for LogicalBlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lb{}", self.0)
     }
 }
 
 impl fmt::Display for LogicalBlockSize {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            LogicalBlockSize::Lb512 => write!(f, "512"),
-            LogicalBlockSize::Lb4096 => write!(f, "4096"),
-        }
+        write!(f, "{}", self.0)
     }
 }
 
@@ -65,7 +83,85 @@ impl fmt::Display for LogicalBlockSize {
 /// let gpt_disk = gpt::disk::read_disk(diskpath).unwrap();
 /// println!("{:#?}", gpt_disk);
 /// ```
-pub fn read_disk(diskpath: &path::Path) -> io::Result<GptDisk> {
+pub fn read_disk(diskpath: &path::Path) -> io::Result<GptDisk<std::fs::File>> {
     let cfg = GptConfig::new();
     cfg.open(diskpath)
 }
+
+/// Detect the logical block size of a GPT disk.
+///
+/// This probes for a valid GPT header at LBA1 for every supported
+/// sector size (up to `MAX_SECTOR_SIZE`) that fits in the device, and
+/// cross-checks its `current_lba` and `backup_lba` against the device
+/// length. Exactly one sector size must match, otherwise an error is returned.
+///
+/// If no header has its backup within the device, as in truncated images
+/// or ones built for a larger target (see `GptConfig::virtual_disk_size`),
+/// the primary header is validated alone instead.
+pub fn detect_logical_block_size<D: Read + Seek>(device: &mut D) -> io::Result<LogicalBlockSize> {
+    let cur = device.seek(SeekFrom::Current(0))?;
+    let len = device.seek(SeekFrom::End(0))?;
+    let mut candidates = Vec::new();
+    let mut primary_only = Vec::new();
+    let mut size = MIN_SECTOR_SIZE;
+    // Protective MBR and primary header must both fit in the device.
+    while size <= MAX_SECTOR_SIZE && size * 2 <= len {
+        let lb_size = LogicalBlockSize::try_from(size)?;
+        if let Ok(h) = header::file_read_header(device, size) {
+            let total_lbs = len / size;
+            trace!(
+                "found GPT signature at lb_size {}: current_lba={} backup_lba={} total_lbs={}",
+                lb_size, h.current_lba, h.backup_lba, total_lbs
+            );
+            if h.current_lba == 1 && h.backup_lba > 1 {
+                if h.backup_lba < total_lbs {
+                    candidates.push(lb_size);
+                } else if h.validate(lb_size, header::DEFAULT_MAX_ARRAY_SIZE).is_ok() {
+                    primary_only.push(lb_size);
+                }
+            }
+        }
+        size *= 2;
+    }
+    device.seek(SeekFrom::Start(cur))?;
+    if candidates.is_empty() {
+        candidates = primary_only;
+    }
+
+    match candidates.as_slice() {
+        [lb_size] => {
+            debug!("detected logical block size: {}", lb_size);
+            Ok(*lb_size)
+        }
+        [] => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "no valid GPT header found at any supported logical block size",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("ambiguous logical block size, valid GPT headers found for: {:?}", candidates),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::LogicalBlockSize;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_lb_size_try_from() {
+        for size in &[512, 1024, 2048, 4096, 8192, 65536] {
+            let lb = LogicalBlockSize::try_from(*size).unwrap();
+            assert_eq!(Into::<u64>::into(lb), *size);
+            assert_eq!(Into::<usize>::into(lb), *size as usize);
+            assert_eq!(lb.to_string(), size.to_string());
+        }
+        assert_eq!(LogicalBlockSize::try_from(512).unwrap(), LogicalBlockSize::Lb512);
+        assert_eq!(LogicalBlockSize::try_from(4096).unwrap(), LogicalBlockSize::Lb4096);
+
+        for size in &[0, 1, 256, 511, 1000, 3072, 4097, 131072, 1 << 40] {
+            LogicalBlockSize::try_from(*size).unwrap_err();
+        }
+    }
+}
@@ -3,6 +3,7 @@
 use crc::{crc32, Hasher32};
 use log::*;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
@@ -11,6 +12,12 @@ use std::path::Path;
 use crate::disk;
 use crate::partition;
 
+/// Size (in bytes) of a GPT header, as written by this library.
+pub const HEADER_SIZE: usize = 92;
+
+/// Default upper bound (in bytes) for a partition entry array read from disk.
+pub const DEFAULT_MAX_ARRAY_SIZE: u64 = 1024 * 1024;
+
 /// Header describing a GPT disk.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Header {
@@ -80,7 +87,7 @@ impl Header {
             None => 128,
         };
 
-        let part_array_num_bytes = u64::from(parts * part_size);
+        let part_array_num_bytes = u64::from(parts) * u64::from(part_size);
         // If not an exact multiple of a sector, round up to the next # of whole sectors.
         let lb_size_u64 = Into::<u64>::into(lb_size);
         let part_array_num_lbs = (part_array_num_bytes + (lb_size_u64 - 1)) / lb_size_u64;
@@ -195,29 +202,151 @@ impl Header {
         lba: u64,
         lb_size: disk::LogicalBlockSize,
     ) -> Result<usize> {
-        // Build up byte array in memory
         let parts_checksum = partentry_checksum(file, self, lb_size)?;
         trace!("computed partitions CRC32: {:#x}", parts_checksum);
-        let bytes = self.as_bytes(None, Some(parts_checksum))?;
-        trace!("bytes before checksum: {:?}", bytes);
-
-        // Calculate the CRC32 from the byte array
-        let checksum = calculate_crc32(&bytes);
-        trace!("computed header CRC32: {:#x}", checksum);
+        let mut hdr = self.clone();
+        hdr.current_lba = lba;
+        hdr.crc32_parts = parts_checksum;
+        hdr.write_to_device(file, lb_size)
+    }
 
-        // Write it to disk in 1 shot
-        let start = lba
+    /// Write this header to its `current_lba` on the given device.
+    ///
+    /// Unlike `write_primary` and `write_backup`, this does not read the
+    /// partition array back from the device: `crc32_parts` must already
+    /// hold the checksum of the array (see `partition::partitions_checksum`).
+    /// The header CRC32 is always recomputed.
+    pub fn write_to_device<D: Write + Seek>(
+        &self,
+        device: &mut D,
+        lb_size: disk::LogicalBlockSize,
+    ) -> Result<usize> {
+        let start = self
+            .current_lba
             .checked_mul(lb_size.into())
             .ok_or_else(|| Error::new(ErrorKind::Other, "header overflow - offset"))?;
         trace!("Seeking to {}", start);
-        let _ = file.seek(SeekFrom::Start(start))?;
-        let mut header_bytes = self.as_bytes(Some(checksum), Some(parts_checksum))?;
+        let _ = device.seek(SeekFrom::Start(start))?;
+        let mut header_bytes = self.to_bytes()?;
         // Per the spec, the rest of the logical block must be zeros...
         header_bytes.resize(Into::<usize>::into(lb_size), 0x00);
-        let len = file.write(&header_bytes)?;
-        trace!("Wrote {} bytes", len);
+        device.write_all(&header_bytes)?;
+        trace!("Wrote {} bytes", header_bytes.len());
+
+        Ok(header_bytes.len())
+    }
+
+    /// Parse a GPT header from its on-disk byte representation.
+    ///
+    /// The buffer must hold at least the 92 bytes of the header; both
+    /// the signature and the header CRC32 are checked.
+    pub fn from_bytes(buf: &[u8]) -> Result<Header> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::Other, "invalid GPT header length"));
+        }
+        let hdr = &buf[..HEADER_SIZE];
+        let mut reader = Cursor::new(hdr);
+
+        let sigstr = String::from_utf8_lossy(&hdr[0..8]);
+        reader.seek(SeekFrom::Current(8))?;
+
+        if sigstr != "EFI PART" {
+            return Err(Error::new(ErrorKind::Other, "invalid GPT signature"));
+        };
+
+        let h = Header {
+            signature: sigstr.to_string(),
+            revision: u32::from_le_bytes(read_exact_buff!(rev, reader, 4)),
+            header_size_le: u32::from_le_bytes(read_exact_buff!(hsle, reader, 4)),
+            crc32: u32::from_le_bytes(read_exact_buff!(crc32, reader, 4)),
+            reserved: u32::from_le_bytes(read_exact_buff!(reserv, reader, 4)),
+            current_lba: u64::from_le_bytes(read_exact_buff!(clba, reader, 8)),
+            backup_lba: u64::from_le_bytes(read_exact_buff!(blba, reader, 8)),
+            first_usable: u64::from_le_bytes(read_exact_buff!(fusable, reader, 8)),
+            last_usable: u64::from_le_bytes(read_exact_buff!(lusable, reader, 8)),
+            disk_guid: parse_uuid(&mut reader)?,
+            part_start: u64::from_le_bytes(read_exact_buff!(pstart, reader, 8)),
+            // Note: this will always return the total number of partition entries
+            // in the array, not how many are actually used
+            num_parts: u32::from_le_bytes(read_exact_buff!(nparts, reader, 4)),
+            part_size: u32::from_le_bytes(read_exact_buff!(partsize, reader, 4)),
+            crc32_parts: u32::from_le_bytes(read_exact_buff!(crc32parts, reader, 4)),
+        };
+        trace!("header: {:?}", hdr);
+        trace!("header gpt: {}", h.disk_guid.to_hyphenated().to_string());
+        let mut hdr_crc = hdr.to_vec();
+        for crc_byte in hdr_crc.iter_mut().skip(16).take(4) {
+            *crc_byte = 0;
+        }
+        let c = calculate_crc32(&hdr_crc);
+        trace!("header CRC32: {:#x} - computed CRC32: {:#x}", h.crc32, c);
+        if c == h.crc32 {
+            Ok(h)
+        } else {
+            Err(Error::new(ErrorKind::Other, "invalid CRC32 checksum"))
+        }
+    }
+
+    /// Serialize this header to its 92 bytes on-disk representation.
+    ///
+    /// The header CRC32 is computed on the fly, while the partition
+    /// array CRC32 is taken as-is from `crc32_parts`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let checksum = self.checksum()?;
+        self.as_bytes(Some(checksum), Some(self.crc32_parts))
+    }
+
+    /// Return the size (in bytes) of the partition entry array.
+    pub fn array_size(&self) -> Result<u64> {
+        u64::from(self.num_parts)
+            .checked_mul(self.part_size.into())
+            .ok_or_else(|| Error::new(ErrorKind::Other, "partition array overflow - size"))
+    }
+
+    /// Check header fields against sane bounds, before trusting them.
+    ///
+    /// This rejects headers with an undersized or oversized header or
+    /// entry size, a partition entry array larger than `max_array_size`
+    /// bytes, and LBAs that are inconsistent or overflow when converted
+    /// to byte offsets.
+    pub fn validate(&self, lb_size: disk::LogicalBlockSize, max_array_size: u64) -> Result<()> {
+        let lb_bytes: u64 = lb_size.into();
+        if self.header_size_le < HEADER_SIZE as u32 || u64::from(self.header_size_le) > lb_bytes {
+            return Err(Error::new(ErrorKind::Other, "invalid header size"));
+        }
+        if self.part_size < partition::ENTRY_SIZE as u32 || self.part_size % 8 != 0 {
+            return Err(Error::new(ErrorKind::Other, "invalid partition entry size"));
+        }
+        let array_size = self.array_size()?;
+        if array_size > max_array_size {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("partition array too large ({} > {} bytes)", array_size, max_array_size),
+            ));
+        }
+        let array_lbs = (array_size + lb_bytes - 1) / lb_bytes;
+        self.part_start
+            .checked_add(array_lbs)
+            .and_then(|end| end.checked_mul(lb_bytes))
+            .ok_or_else(|| Error::new(ErrorKind::Other, "partition array overflow - offset"))?;
+        self.current_lba
+            .max(self.backup_lba)
+            .max(self.last_usable)
+            .checked_add(1)
+            .and_then(|end| end.checked_mul(lb_bytes))
+            .ok_or_else(|| Error::new(ErrorKind::Other, "header overflow - LBA offset"))?;
+        if self.first_usable > self.last_usable.saturating_add(1) {
+            return Err(Error::new(ErrorKind::Other, "invalid usable LBA range"));
+        }
+        Ok(())
+    }
 
-        Ok(len)
+    /// Compute the header CRC32, as it would be written by `to_bytes`.
+    pub fn checksum(&self) -> Result<u32> {
+        let bytes = self.as_bytes(None, Some(self.crc32_parts))?;
+        let checksum = calculate_crc32(&bytes);
+        trace!("computed header CRC32: {:#x}", checksum);
+        Ok(checksum)
     }
 
     fn as_bytes(
@@ -228,7 +357,11 @@ impl Header {
         let mut buff: Vec<u8> = Vec::new();
         let disk_guid_fields = self.disk_guid.as_fields();
 
-        buff.write_all(self.signature.as_bytes())?;
+        let mut signature = [0u8; 8];
+        for (dst, src) in signature.iter_mut().zip(self.signature.as_bytes()) {
+            *dst = *src;
+        }
+        buff.write_all(&signature)?;
         buff.write_all(&self.revision.to_le_bytes())?;
         buff.write_all(&self.header_size_le.to_le_bytes())?;
         match header_checksum {
@@ -260,13 +393,8 @@ pub fn parse_uuid(rdr: &mut Cursor<&[u8]>) -> Result<uuid::Uuid> {
     let d1: u32 = u32::from_le_bytes(read_exact_buff!(d1b, rdr, 4));
     let d2: u16 = u16::from_le_bytes(read_exact_buff!(d2b, rdr, 2));
     let d3: u16 = u16::from_le_bytes(read_exact_buff!(d3b, rdr, 2));
-    let uuid = uuid::Uuid::from_fields(
-        d1,
-        d2,
-        d3,
-        &rdr.get_ref()[rdr.position() as usize..rdr.position() as usize + 8],
-    );
-    rdr.seek(SeekFrom::Current(8))?;
+    let d4 = read_exact_buff!(d4b, rdr, 8);
+    let uuid = uuid::Uuid::from_fields(d1, d2, d3, &d4);
 
     match uuid {
         Ok(uuid) => Ok(uuid),
@@ -320,12 +448,21 @@ pub(crate) fn read_primary_header<D: Read + Seek>(
     res
 }
 
+#[cfg(test)]
 pub(crate) fn read_backup_header<D: Read + Seek>(
     file: &mut D,
     sector_size: disk::LogicalBlockSize,
 ) -> Result<Header> {
-    let cur = file.seek(SeekFrom::Current(0)).unwrap_or(0);
     let h2sect = find_backup_lba(file, sector_size)?;
+    read_backup_header_at(file, sector_size, h2sect)
+}
+
+pub(crate) fn read_backup_header_at<D: Read + Seek>(
+    file: &mut D,
+    sector_size: disk::LogicalBlockSize,
+    h2sect: u64,
+) -> Result<Header> {
+    let cur = file.seek(SeekFrom::Current(0)).unwrap_or(0);
     let offset = h2sect
         .checked_mul(sector_size.into())
         .ok_or_else(|| Error::new(ErrorKind::Other, "backup header overflow - offset"))?;
@@ -334,53 +471,31 @@ pub(crate) fn read_backup_header<D: Read + Seek>(
     res
 }
 
-pub(crate) fn file_read_header<D: Read + Seek>(file: &mut D, offset: u64) -> Result<Header> {
-    let _ = file.seek(SeekFrom::Start(offset));
-    let mut hdr: [u8; 92] = [0; 92];
-
-    let _ = file.read_exact(&mut hdr);
-    let mut reader = Cursor::new(&hdr[..]);
-
-    let sigstr = String::from_utf8_lossy(
-        &reader.get_ref()[reader.position() as usize..reader.position() as usize + 8],
-    );
-    reader.seek(SeekFrom::Current(8))?;
+/// Whether LBA `lba` starts with a GPT header signature, valid or not.
+///
+/// Sectors past the end of the device hold no header.
+pub(crate) fn has_header_at<D: Read + Seek>(
+    file: &mut D,
+    sector_size: disk::LogicalBlockSize,
+    lba: u64,
+) -> bool {
+    let cur = file.seek(SeekFrom::Current(0)).unwrap_or(0);
+    let mut sig = [0u8; 8];
+    let res = lba
+        .checked_mul(sector_size.into())
+        .ok_or_else(|| Error::new(ErrorKind::Other, "header overflow - offset"))
+        .and_then(|offset| file.seek(SeekFrom::Start(offset)))
+        .and_then(|_| file.read_exact(&mut sig));
+    let _ = file.seek(SeekFrom::Start(cur));
+    res.is_ok() && &sig == b"EFI PART"
+}
 
-    if sigstr != "EFI PART" {
-        return Err(Error::new(ErrorKind::Other, "invalid GPT signature"));
-    };
+pub(crate) fn file_read_header<D: Read + Seek>(file: &mut D, offset: u64) -> Result<Header> {
+    let _ = file.seek(SeekFrom::Start(offset))?;
+    let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
 
-    let h = Header {
-        signature: sigstr.to_string(),
-        revision: u32::from_le_bytes(read_exact_buff!(rev, reader, 4)),
-        header_size_le: u32::from_le_bytes(read_exact_buff!(hsle, reader, 4)),
-        crc32: u32::from_le_bytes(read_exact_buff!(crc32, reader, 4)),
-        reserved: u32::from_le_bytes(read_exact_buff!(reserv, reader, 4)),
-        current_lba: u64::from_le_bytes(read_exact_buff!(clba, reader, 8)),
-        backup_lba: u64::from_le_bytes(read_exact_buff!(blba, reader, 8)),
-        first_usable: u64::from_le_bytes(read_exact_buff!(fusable, reader, 8)),
-        last_usable: u64::from_le_bytes(read_exact_buff!(lusable, reader, 8)),
-        disk_guid: parse_uuid(&mut reader)?,
-        part_start: u64::from_le_bytes(read_exact_buff!(pstart, reader, 8)),
-        // Note: this will always return the total number of partition entries
-        // in the array, not how many are actually used
-        num_parts: u32::from_le_bytes(read_exact_buff!(nparts, reader, 4)),
-        part_size: u32::from_le_bytes(read_exact_buff!(partsize, reader, 4)),
-        crc32_parts: u32::from_le_bytes(read_exact_buff!(crc32parts, reader, 4)),
-    };
-    trace!("header: {:?}", &hdr[..]);
-    trace!("header gpt: {}", h.disk_guid.to_hyphenated().to_string());
-    let mut hdr_crc = hdr;
-    for crc_byte in hdr_crc.iter_mut().skip(16).take(4) {
-        *crc_byte = 0;
-    }
-    let c = calculate_crc32(&hdr_crc);
-    trace!("header CRC32: {:#x} - computed CRC32: {:#x}", h.crc32, c);
-    if c == h.crc32 {
-        Ok(h)
-    } else {
-        Err(Error::new(ErrorKind::Other, "invalid CRC32 checksum"))
-    }
+    file.read_exact(&mut hdr)?;
+    Header::from_bytes(&hdr)
 }
 
 pub(crate) fn find_backup_lba<D: Read + Seek>(
@@ -388,10 +503,15 @@ pub(crate) fn find_backup_lba<D: Read + Seek>(
     sector_size: disk::LogicalBlockSize,
 ) -> Result<u64> {
     trace!("querying file size to find backup header location");
-    let lb_size: u64 = sector_size.into();
     let old_pos = f.seek(std::io::SeekFrom::Current(0))?;
     let len = f.seek(std::io::SeekFrom::End(0))?;
     f.seek(std::io::SeekFrom::Start(old_pos))?;
+    backup_lba_for_size(len, sector_size)
+}
+
+/// Return the LBA of the backup header for a disk of `len` bytes.
+pub(crate) fn backup_lba_for_size(len: u64, sector_size: disk::LogicalBlockSize) -> Result<u64> {
+    let lb_size: u64 = sector_size.into();
     if len <= lb_size {
         return Err(Error::new(
             ErrorKind::Other,
@@ -422,6 +542,8 @@ pub(crate) fn partentry_checksum<D: Read + Seek>(
     hdr: &Header,
     lb_size: disk::LogicalBlockSize,
 ) -> Result<u32> {
+    // The array length comes from the header, bound it before allocating.
+    hdr.validate(lb_size, DEFAULT_MAX_ARRAY_SIZE)?;
     // Seek to start of partition table.
     trace!("Computing partition checksum");
     let start = hdr
@@ -436,7 +558,9 @@ pub(crate) fn partentry_checksum<D: Read + Seek>(
         .checked_mul(hdr.part_size.into())
         .ok_or_else(|| Error::new(ErrorKind::Other, "partition table - size"))?;
     trace!("Reading {} bytes", pt_len);
-    let mut buf = vec![0; pt_len as usize];
+    let pt_len = usize::try_from(pt_len)
+        .map_err(|_| Error::new(ErrorKind::Other, "partition table - size"))?;
+    let mut buf = vec![0; pt_len];
     file.read_exact(&mut buf)?;
 
     //trace!("Buffer before checksum: {:?}", buf);
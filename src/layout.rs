@@ -0,0 +1,485 @@
+//! Declarative partition layouts.
+//!
+//! A `Layout` lists the partitions a disk should have. Reconciling it with
+//! an existing table computes a `Plan`: the smallest list of operations
+//! (add, resize, retype, rename, change attributes and, if allowed,
+//! delete) that turns the table into the layout, without moving any
+//! partition. The plan can be displayed for review before it is applied.
+//!
+//! Each layout entry is matched with at most one existing partition:
+//!
+//!  * entries with a fixed GUID only match the partition with that GUID;
+//!  * other entries match a partition with the same name, then the
+//!    remaining ones match partitions of the same type (the n-th entry of
+//!    a type matches the n-th partition of that type on disk).
+//!
+//! Matched partitions keep their GUID and their start, and are resized in
+//! place. Unmatched entries become new partitions, placed in the first
+//! free area that fits them. An entry of `Size::Remaining` takes the
+//! largest free area when it is new, or grows into the free space directly
+//! after it when it exists; this happens once other partitions are placed.
+
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::partition::Partition;
+use crate::partition_types::Type;
+use crate::table::{self, GptTable};
+
+/// Wanted partitions of a disk.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Layout {
+    /// Partition entries, in the order new partitions are allocated.
+    pub entries: Vec<LayoutEntry>,
+    /// Whether partitions not in the layout are deleted. Otherwise,
+    /// reconciling a disk that has such partitions fails.
+    pub allow_delete: bool,
+}
+
+/// One wanted partition.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayoutEntry {
+    /// Partition name.
+    pub name: String,
+    /// Partition type.
+    pub part_type: Type,
+    /// Partition size.
+    pub size: Size,
+    /// How the partition GUID is chosen, and partitions matched.
+    pub guid: GuidPolicy,
+    /// Partition attribute bits.
+    pub flags: u64,
+}
+
+/// Size of a layout entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Size {
+    /// Size in bytes, rounded up to whole logical blocks.
+    Bytes(u64),
+    /// All the free space available to the partition; at most one entry
+    /// of a layout can use it.
+    Remaining,
+}
+
+/// How the GUID of a layout entry is chosen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuidPolicy {
+    /// Keep the GUID of the matched partition, or generate a random one
+    /// for a new partition.
+    Random,
+    /// Only match the partition with this GUID, or create it with it.
+    Fixed(uuid::Uuid),
+}
+
+impl LayoutEntry {
+    /// Create an entry with a random GUID and no attributes.
+    pub fn new(name: &str, part_type: Type, size: Size) -> Self {
+        LayoutEntry {
+            name: name.to_string(),
+            part_type,
+            size,
+            guid: GuidPolicy::Random,
+            flags: 0,
+        }
+    }
+}
+
+/// A single change of a plan.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Operation {
+    /// Delete a partition not in the layout.
+    Delete {
+        /// Partition id.
+        id: u32,
+        /// Partition name.
+        name: String,
+    },
+    /// Rename a partition.
+    Rename {
+        /// Partition id.
+        id: u32,
+        /// Current name.
+        from: String,
+        /// New name.
+        to: String,
+    },
+    /// Change the type of a partition.
+    Retype {
+        /// Partition id.
+        id: u32,
+        /// Current type.
+        from: Type,
+        /// New type.
+        to: Type,
+    },
+    /// Change the attribute bits of a partition.
+    SetAttributes {
+        /// Partition id.
+        id: u32,
+        /// Current attribute bits.
+        from: u64,
+        /// New attribute bits.
+        to: u64,
+    },
+    /// Move the end of a partition, keeping its start.
+    Resize {
+        /// Partition id.
+        id: u32,
+        /// Current last LBA.
+        from: u64,
+        /// New last LBA.
+        to: u64,
+    },
+    /// Add a partition.
+    Add {
+        /// Partition id.
+        id: u32,
+        /// The new partition.
+        partition: Partition,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Delete { id, name } => write!(f, "delete partition {} ({:?})", id, name),
+            Operation::Rename { id, from, to } => {
+                write!(f, "rename partition {}: {:?} -> {:?}", id, from, to)
+            }
+            Operation::Retype { id, from, to } => {
+                write!(f, "retype partition {}: {} -> {}", id, from.guid, to.guid)
+            }
+            Operation::SetAttributes { id, from, to } => {
+                write!(
+                    f,
+                    "set attributes of partition {}: {:#x} -> {:#x}",
+                    id, from, to
+                )
+            }
+            Operation::Resize { id, from, to } => {
+                write!(f, "resize partition {}: last LBA {} -> {}", id, from, to)
+            }
+            Operation::Add { id, partition } => write!(
+                f,
+                "add partition {} ({:?}): LBAs {}-{}, type {}",
+                id,
+                partition.name,
+                partition.first_lba,
+                partition.last_lba,
+                partition.part_type_guid.guid
+            ),
+        }
+    }
+}
+
+/// Operations turning a table into a layout, and the resulting table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Plan {
+    base: GptTable,
+    target: GptTable,
+    operations: Vec<Operation>,
+}
+
+impl Plan {
+    /// Operations of this plan, in the order they are applied.
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Whether the table already matches the layout.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Table the plan was computed from.
+    pub fn base(&self) -> &GptTable {
+        &self.base
+    }
+
+    /// Table once the plan is applied.
+    pub fn target(&self) -> &GptTable {
+        &self.target
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operations.is_empty() {
+            return writeln!(f, "no changes");
+        }
+        for op in &self.operations {
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+impl Layout {
+    /// Compute the plan turning `table` into this layout.
+    pub fn plan(&self, table: &GptTable) -> io::Result<Plan> {
+        self.check()?;
+        let lb_size: u64 = (*table.logical_block_size()).into();
+        let mut parts: BTreeMap<u32, Partition> = table
+            .partitions()
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(id, p)| (*id, p.clone()))
+            .collect();
+        let matches = self.match_partitions(&parts);
+        let mut operations = Vec::new();
+
+        let unmatched: Vec<u32> = parts
+            .keys()
+            .filter(|id| !matches.contains(&Some(**id)))
+            .cloned()
+            .collect();
+        for id in unmatched {
+            if !self.allow_delete {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "partition {} ({:?}) is not in the layout",
+                        id, parts[&id].name
+                    ),
+                ));
+            }
+            if let Some(part) = parts.remove(&id) {
+                operations.push(Operation::Delete {
+                    id,
+                    name: part.name,
+                });
+            }
+        }
+
+        for (entry, id) in self.entries.iter().zip(&matches) {
+            let (id, part) = match id.and_then(|id| parts.get_mut(&id).map(|p| (id, p))) {
+                Some(matched) => matched,
+                None => continue,
+            };
+            if part.name != entry.name {
+                operations.push(Operation::Rename {
+                    id,
+                    from: part.name.clone(),
+                    to: entry.name.clone(),
+                });
+                part.name = entry.name.clone();
+            }
+            if part.part_type_guid != entry.part_type {
+                operations.push(Operation::Retype {
+                    id,
+                    from: part.part_type_guid.clone(),
+                    to: entry.part_type.clone(),
+                });
+                part.part_type_guid = entry.part_type.clone();
+            }
+            if part.flags != entry.flags {
+                operations.push(Operation::SetAttributes {
+                    id,
+                    from: part.flags,
+                    to: entry.flags,
+                });
+                part.flags = entry.flags;
+            }
+        }
+
+        let mut resizes = BTreeMap::new();
+        for (entry, id) in self.entries.iter().zip(&matches) {
+            if let (Size::Bytes(bytes), Some(id)) = (entry.size, id) {
+                let first_lba = parts[id].first_lba;
+                resizes.insert(*id, first_lba + (bytes + lb_size - 1) / lb_size - 1);
+            }
+        }
+        for (id, last_lba) in &resizes {
+            let limit = next_start(&parts, parts[id].first_lba).unwrap_or(table.last_usable() + 1);
+            if *last_lba >= limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("not enough space after partition {} to resize it", id),
+                ));
+            }
+        }
+        for (id, last_lba) in resizes {
+            if let Some(part) = parts.get_mut(&id).filter(|p| p.last_lba != last_lba) {
+                operations.push(Operation::Resize {
+                    id,
+                    from: part.last_lba,
+                    to: last_lba,
+                });
+                part.last_lba = last_lba;
+            }
+        }
+
+        let mut added = Vec::new();
+        for (entry, _) in self
+            .entries
+            .iter()
+            .zip(&matches)
+            .filter(|(_, id)| id.is_none())
+        {
+            if let Size::Bytes(bytes) = entry.size {
+                let size = (bytes + lb_size - 1) / lb_size * lb_size;
+                let id = table::add_partition(
+                    &mut parts,
+                    table.first_usable(),
+                    table.last_usable(),
+                    *table.logical_block_size(),
+                    &entry.name,
+                    size,
+                    entry.part_type.clone(),
+                    entry.flags,
+                )
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("adding partition {:?}: {}", entry.name, e),
+                    )
+                })?;
+                added.push((entry, id));
+            }
+        }
+
+        if let Some((entry, id)) = self
+            .entries
+            .iter()
+            .zip(&matches)
+            .find(|(entry, _)| entry.size == Size::Remaining)
+        {
+            match id {
+                Some(id) => {
+                    let limit =
+                        next_start(&parts, parts[id].first_lba).unwrap_or(table.last_usable() + 1);
+                    if let Some(part) = parts.get_mut(id).filter(|p| p.last_lba + 1 < limit) {
+                        operations.push(Operation::Resize {
+                            id: *id,
+                            from: part.last_lba,
+                            to: limit - 1,
+                        });
+                        part.last_lba = limit - 1;
+                    }
+                }
+                None => {
+                    let (first_lba, len) =
+                        table::find_free_sectors(&parts, table.first_usable(), table.last_usable())
+                            .into_iter()
+                            // Reversed, so that the first of equally large areas wins.
+                            .rev()
+                            .max_by_key(|(_, len)| *len)
+                            .ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::Other,
+                                    format!("no free space left for partition {:?}", entry.name),
+                                )
+                            })?;
+                    let id = table::find_next_partition_id(&parts);
+                    parts.insert(
+                        id,
+                        Partition {
+                            part_type_guid: entry.part_type.clone(),
+                            part_guid: uuid::Uuid::new_v4(),
+                            first_lba,
+                            last_lba: first_lba + len - 1,
+                            flags: entry.flags,
+                            name: entry.name.clone(),
+                        },
+                    );
+                    added.push((entry, id));
+                }
+            }
+        }
+
+        added.sort_by_key(|(_, id)| *id);
+        for (entry, id) in added {
+            if let Some(part) = parts.get_mut(&id) {
+                if let GuidPolicy::Fixed(guid) = entry.guid {
+                    part.part_guid = guid;
+                }
+                operations.push(Operation::Add {
+                    id,
+                    partition: part.clone(),
+                });
+            }
+        }
+
+        let mut target = table.clone();
+        target.update_partitions(parts);
+        target.validate()?;
+        debug!("layout plan: {} operations", operations.len());
+        Ok(Plan {
+            base: table.clone(),
+            target,
+            operations,
+        })
+    }
+
+    /// Check that entries can be told apart and that at most one of them
+    /// takes the remaining space.
+    fn check(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::Other, msg));
+        for (n, entry) in self.entries.iter().enumerate() {
+            let others = &self.entries[n + 1..];
+            if entry.size == Size::Bytes(0) {
+                return invalid(format!("partition {:?} has an empty size", entry.name));
+            }
+            if entry.size == Size::Remaining && others.iter().any(|e| e.size == Size::Remaining) {
+                return invalid("only one partition can take the remaining space".to_string());
+            }
+            if others.iter().any(|e| e.name == entry.name) {
+                return invalid(format!(
+                    "duplicate partition name {:?} in layout",
+                    entry.name
+                ));
+            }
+            if let GuidPolicy::Fixed(guid) = entry.guid {
+                if others.iter().any(|e| e.guid == GuidPolicy::Fixed(guid)) {
+                    return invalid(format!("duplicate partition GUID {} in layout", guid));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Match each entry with an existing partition, see the module
+    /// documentation.
+    fn match_partitions(&self, parts: &BTreeMap<u32, Partition>) -> Vec<Option<u32>> {
+        let mut matches: Vec<Option<u32>> = self
+            .entries
+            .iter()
+            .map(|entry| match entry.guid {
+                GuidPolicy::Fixed(guid) => parts
+                    .iter()
+                    .find(|(_, p)| p.part_guid == guid)
+                    .map(|(id, _)| *id),
+                GuidPolicy::Random => None,
+            })
+            .collect();
+
+        let mut by_position: Vec<(&u32, &Partition)> = parts.iter().collect();
+        by_position.sort_by_key(|(_, p)| p.first_lba);
+        let by_name: fn(&Partition, &LayoutEntry) -> bool = |p, entry| p.name == entry.name;
+        let by_type: fn(&Partition, &LayoutEntry) -> bool =
+            |p, entry| p.part_type_guid == entry.part_type;
+        for same in &[by_name, by_type] {
+            for (n, entry) in self.entries.iter().enumerate() {
+                if entry.guid != GuidPolicy::Random || matches[n].is_some() {
+                    continue;
+                }
+                matches[n] = by_position
+                    .iter()
+                    .find(|(id, p)| !matches.contains(&Some(**id)) && same(p, entry))
+                    .map(|(id, _)| **id);
+            }
+        }
+        matches
+    }
+}
+
+/// First LBA of the partition following `lba`, if any.
+fn next_start(parts: &BTreeMap<u32, Partition>, lba: u64) -> Option<u64> {
+    parts
+        .values()
+        .map(|p| p.first_lba)
+        .filter(|first| *first > lba)
+        .min()
+}
@@ -5,7 +5,7 @@
 //!
 //! ```
 //! extern crate gpt;
-//! use std::convert::TryFrom;
+//! use std::io::{Read, Seek};
 //!
 //! fn inspect_disk() {
 //!     let diskpath = std::path::Path::new("/dev/sdz");
@@ -33,26 +33,12 @@
 //! /// Demonstrates how to create a new partition table without anything pre-existing
 //! fn create_partition_in_ram() {
 //!     const TOTAL_BYTES: usize = 1024 * 64;
-//!     let mut mem_device = Box::new(std::io::Cursor::new(vec![0u8; TOTAL_BYTES]));
+//!     let mem_device = std::io::Cursor::new(vec![0u8; TOTAL_BYTES]);
 //!
-//!     // Create a protective MBR at LBA0
-//!     let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
-//!         u32::try_from((TOTAL_BYTES / 512) - 1).unwrap_or(0xFF_FF_FF_FF));
-//!     mbr.overwrite_lba0(&mut mem_device).expect("failed to write MBR");
+//!     // Write a protective MBR and a blank partition table
+//!     let mut gdisk = gpt::GptDisk::create(mem_device, gpt::CreateOptions::default())
+//!         .expect("failed to create GptDisk");
 //!
-//!     let mut gdisk = gpt::GptConfig::default()
-//!         .initialized(false)
-//!         .writable(true)
-//!         .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
-//!         .create_from_device(mem_device, None)
-//!         .expect("failed to crate GptDisk");
-//!
-//!     // Initialize the headers using a blank partition table
-//!     gdisk.update_partitions(
-//!         std::collections::BTreeMap::<u32, gpt::partition::Partition>::new()
-//!     ).expect("failed to initialize blank partition table");
-//!
-//!     // At this point, gdisk.primary_header() and gdisk.backup_header() are populated...
 //!     // Add a few partitions to demonstrate how...
 //!     gdisk.add_partition("test1", 1024 * 12, gpt::partition_types::BASIC, 0)
 //!         .expect("failed to add test1 partition");
@@ -76,40 +62,61 @@
 
 use log::*;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::io::{Read, Seek, Write};
 use std::{fs, io, path};
 
 #[macro_use]
 mod macros;
+pub mod device;
 pub mod disk;
 pub mod header;
+pub mod layout;
 pub mod mbr;
 pub mod partition;
 pub mod partition_types;
+pub mod table;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+/// Size of the records in `sgdisk --backup` files, regardless of the
+/// disk logical block size.
+const SGDISK_BACKUP_UNIT: usize = 512;
 
 /// A generic device that we can read/write partitions from/to.
 pub trait DiskDevice: Read + Write + Seek + std::fmt::Debug {}
 /// Implement the DiskDevice trait for anything that meets the
 /// requirements, e.g., `std::fs::File`
 impl<T> DiskDevice for T where T: Read + Write + Seek + std::fmt::Debug {}
-/// A dynamic trait object that is used by GptDisk for reading/writing/seeking.
+/// A dynamic trait object that can be used by GptDisk for reading/writing/seeking.
 pub type DiskDeviceObject<'a> = Box<dyn DiskDevice + 'a>;
+/// A GPT disk backed by a dynamic trait object.
+pub type GptDiskObject<'a> = GptDisk<DiskDeviceObject<'a>>;
 
 /// Configuration options to open a GPT disk.
 #[derive(Debug, Eq, PartialEq)]
 pub struct GptConfig {
     /// Logical block size.
     lb_size: disk::LogicalBlockSize,
+    /// Whether to detect the logical block size on open.
+    lb_size_auto: bool,
     /// Whether to open a GPT partition table in writable mode.
     writable: bool,
     /// Whether to expect and parse an initialized disk image.
     initialized: bool,
+    /// Maximum size (in bytes) of a partition entry array read from disk.
+    max_array_size: u64,
+    /// Size (in bytes) of the target disk, if different from the device.
+    virtual_disk_size: Option<u64>,
+    /// Whether to skip writing the backup header and partition array.
+    skip_backup: bool,
+    /// Whether to only write sectors whose content changes.
+    minimal_writes: bool,
+    /// Whether to open a disk whose backup header is present but invalid.
+    ignore_corrupt_backup: bool,
 }
 
 impl GptConfig {
-    // TODO(lucab): complete support for skipping backup
-    // header, etc, then expose all config knobs here.
-
     /// Create a new default configuration.
     pub fn new() -> Self {
         GptConfig::default()
@@ -131,38 +138,148 @@ impl GptConfig {
     /// Size of logical blocks (sectors) for this disk.
     pub fn logical_block_size(mut self, lb_size: disk::LogicalBlockSize) -> Self {
         self.lb_size = lb_size;
+        self.lb_size_auto = false;
+        self
+    }
+
+    /// Detect the size of logical blocks (sectors) when opening an
+    /// initialized disk, see `disk::detect_logical_block_size`.
+    ///
+    /// Uninitialized disks keep using the configured logical block size.
+    pub fn logical_block_size_auto(mut self) -> Self {
+        self.lb_size_auto = true;
+        self
+    }
+
+    /// Maximum size (in bytes) of a partition entry array to accept
+    /// when reading a disk, see `header::DEFAULT_MAX_ARRAY_SIZE`.
+    ///
+    /// This bounds memory usage when inspecting untrusted images.
+    pub fn max_array_size(mut self, max_array_size: u64) -> Self {
+        self.max_array_size = max_array_size;
+        self
+    }
+
+    /// Size (in bytes) of the target disk, when it differs from the device.
+    ///
+    /// Headers are computed for this capacity, with the backup header in
+    /// its last LBA, instead of deriving it from the device length. This
+    /// allows building a table for a device larger than the image being
+    /// written: on write, a file is sparsely extended up to the backup
+    /// header; alternatively, `GptDisk::backup_region()` returns the backup
+    /// region as a separate blob.
+    pub fn virtual_disk_size(mut self, bytes: u64) -> Self {
+        self.virtual_disk_size = Some(bytes);
+        self
+    }
+
+    /// Whether to skip writing the backup GPT (primary-only mode).
+    ///
+    /// When set, `write()` only records the protective MBR, the primary
+    /// header and its partition array. The primary header still points
+    /// at a backup header in the last LBA, which can be written later on
+    /// the target device with `GptDisk::complete_backup()`.
+    pub fn skip_backup(mut self, skip_backup: bool) -> Self {
+        self.skip_backup = skip_backup;
+        self
+    }
+
+    /// Whether to only write sectors whose content actually changes.
+    ///
+    /// When set, `write()` first reads back the header and partition
+    /// array sectors and skips those already holding the new content,
+    /// so that a no-op edit causes no writes at all. The number of
+    /// skipped sectors is reported by `GptDisk::skipped_sectors()`.
+    pub fn minimal_writes(mut self, minimal_writes: bool) -> Self {
+        self.minimal_writes = minimal_writes;
+        self
+    }
+
+    /// Whether to open a disk whose backup header is present but invalid,
+    /// using its primary GPT only.
+    ///
+    /// By default this is an error. A missing backup, as in images written
+    /// in primary-only mode or devices too short to hold one, is always
+    /// accepted.
+    pub fn ignore_corrupt_backup(mut self, ignore_corrupt_backup: bool) -> Self {
+        self.ignore_corrupt_backup = ignore_corrupt_backup;
         self
     }
 
     /// Open the GPT disk at the given path and inspect it according
     /// to configuration options.
-    pub fn open(self, diskpath: &path::Path) -> io::Result<GptDisk> {
-        let file = Box::new(fs::OpenOptions::new()
+    pub fn open(self, diskpath: &path::Path) -> io::Result<GptDisk<fs::File>> {
+        let file = fs::OpenOptions::new()
             .write(self.writable)
             .read(true)
-            .open(diskpath)?);
-        self.open_from_device(file as DiskDeviceObject)
+            .open(diskpath)?;
+        self.open_from_device(file)
     }
 
-    /// Open the GPT disk from the given DiskDeviceObject and
+    /// Open the GPT disk from the given device and
     /// inspect it according to configuration options.
-    pub fn open_from_device(self, mut device: DiskDeviceObject) -> io::Result<GptDisk> {
+    pub fn open_from_device<D: DiskDevice>(mut self, mut device: D) -> io::Result<GptDisk<D>> {
         // Uninitialized disk, no headers/table to parse.
         if !self.initialized {
             return self.create_from_device(device, Some(uuid::Uuid::new_v4()));
         }
 
+        if self.lb_size_auto {
+            self.lb_size = disk::detect_logical_block_size(&mut device)?;
+        }
+
         // Proper GPT disk, fully inspect its layout.
-        let h1 = header::read_primary_header(&mut device, self.lb_size)?;
-        let h2 = header::read_backup_header(&mut device, self.lb_size)?;
-        let table = partition::file_read_partitions(&mut device, &h1, self.lb_size)?;
+        let h1 = header::read_primary_header(&mut device, self.lb_size);
+        let bak = match self.virtual_disk_size {
+            Some(size) => header::backup_lba_for_size(size, self.lb_size),
+            None => header::find_backup_lba(&mut device, self.lb_size),
+        };
+        // Keep whether a header signature is there, to tell a corrupt backup from a missing one.
+        let h2 = match bak {
+            Ok(bak) => header::read_backup_header_at(&mut device, self.lb_size, bak)
+                .and_then(|h| h.validate(self.lb_size, self.max_array_size).map(|_| h))
+                .map_err(|e| (e, header::has_header_at(&mut device, self.lb_size, bak))),
+            Err(e) => Err((e, false)),
+        };
+        let h2 = match h2 {
+            Ok(h) => Some(h),
+            Err((ref e, true)) if !self.ignore_corrupt_backup => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("invalid backup header: {}", e),
+                ));
+            }
+            // Images written in primary-only mode have no backup yet.
+            Err((e, _)) => {
+                warn!("no valid backup header, using primary only: {}", e);
+                None
+            }
+        };
+        let primary = h1.and_then(|h| {
+            partition::file_read_partitions_bounded(&mut device, &h, self.lb_size, self.max_array_size)
+                .map(|pp| (h, pp))
+        });
+        let (h1, table) = match (primary, h2.as_ref()) {
+            (Ok(primary), _) => primary,
+            (Err(e), None) => return Err(e),
+            // e.g. after an interrupted write, recover from the backup copy.
+            (Err(e), Some(h2)) => {
+                warn!("invalid primary GPT, recovering from backup: {}", e);
+                let pp = partition::file_read_partitions_bounded(
+                    &mut device, h2, self.lb_size, self.max_array_size)?;
+                let h1 = table::GptTable::from_header(h2, pp.clone(), self.lb_size).primary_header()?;
+                (h1, pp)
+            }
+        };
         let disk = GptDisk {
             config: self,
             device,
             guid: h1.disk_guid,
             primary_header: Some(h1),
-            backup_header: Some(h2),
+            backup_header: h2,
             partitions: table,
+            converted_from: None,
+            skipped_sectors: 0,
         };
         debug!("disk: {:?}", disk);
         Ok(disk)
@@ -170,11 +287,11 @@ impl GptConfig {
 
     /// Create a GPTDisk with default headers and an empty partition table.
     /// If guid is None then it will generate a new random guid.
-    pub fn create_from_device(
+    pub fn create_from_device<D: DiskDevice>(
         self,
-        device: DiskDeviceObject,
+        device: D,
         guid: Option<uuid::Uuid>
-    ) -> io::Result<GptDisk> {
+    ) -> io::Result<GptDisk<D>> {
         if self.initialized {
             Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -189,34 +306,197 @@ impl GptConfig {
                 primary_header: None,
                 backup_header: None,
                 partitions: BTreeMap::new(),
+                converted_from: None,
+                skipped_sectors: 0,
             };
             Ok(empty)
         }
     }
+
+    /// Restore a partition table from a backup file to `device`, and
+    /// return the resulting disk.
+    ///
+    /// The backup must be in the format written by `sgdisk --backup` or
+    /// `GptDisk::save_backup()`: the MBR, main header and backup header
+    /// in 512 bytes records, followed by the partition array. The main
+    /// header is used if valid, otherwise the backup one. If the device
+    /// size differs from the one in the backup, the backup GPT is moved
+    /// to the end of the device. The configuration must be writable, and
+    /// its logical block size must match the one of the saved disk.
+    pub fn restore_backup<R: Read, D: DiskDevice>(
+        mut self,
+        reader: &mut R,
+        mut device: D,
+    ) -> io::Result<GptDisk<D>> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "disk not opened in writable mode",
+            ));
+        }
+        let mut records = vec![0u8; 3 * SGDISK_BACKUP_UNIT];
+        reader.read_exact(&mut records)?;
+        let mbr_bytes = &records[..SGDISK_BACKUP_UNIT];
+        if mbr_bytes[510..] != [0x55, 0xAA] {
+            return Err(io::Error::new(io::ErrorKind::Other, "invalid MBR signature in backup"));
+        }
+        let h1 = header::Header::from_bytes(&records[SGDISK_BACKUP_UNIT..]);
+        let h2 = header::Header::from_bytes(&records[2 * SGDISK_BACKUP_UNIT..]);
+        let h = match (h1, h2) {
+            (Ok(h1), _) => h1,
+            (Err(e), Ok(h2)) => {
+                warn!("invalid main header in backup, using backup header: {}", e);
+                h2
+            }
+            (Err(e), Err(_)) => return Err(e),
+        };
+        h.validate(self.lb_size, self.max_array_size)?;
+
+        let array_len = usize::try_from(h.array_size()?)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "partitions - size"))?;
+        let mut array = vec![0u8; array_len];
+        reader.read_exact(&mut array)?;
+        if crc::crc32::checksum_ieee(&array) != h.crc32_parts {
+            return Err(io::Error::new(io::ErrorKind::Other, "partition table CRC mismatch"));
+        }
+        let pp = partition::partitions_from_bytes(&array, h.num_parts, h.part_size)?;
+        let mut table = table::GptTable::from_header(&h, pp, self.lb_size);
+        table.validate()?;
+
+        let bak = match self.virtual_disk_size {
+            Some(size) => header::backup_lba_for_size(size, self.lb_size)?,
+            None => header::find_backup_lba(&mut device, self.lb_size)?,
+        };
+        let resized = table.backup_lba() != bak;
+        if resized {
+            warn!(
+                "disk size does not match backup (backup LBA {} vs {}), adjusting",
+                bak,
+                table.backup_lba()
+            );
+            let lb_bytes: u64 = self.lb_size.into();
+            table.retarget((bak + 1) * lb_bytes)?;
+        }
+        device.seek(io::SeekFrom::Start(0))?;
+        device.write_all(mbr_bytes)?;
+        if resized {
+            mbr::ProtectiveMBR::with_lb_size(u32::try_from(bak).unwrap_or(0xFF_FF_FF_FF))
+                .update_conservative(&mut device)?;
+        }
+
+        self.initialized = false;
+        let mut gdisk = self.create_from_device(device, Some(*table.guid()))?;
+        gdisk.update_table(table)?;
+        gdisk.write_inplace()?;
+        Ok(gdisk)
+    }
+}
+
+/// Options for creating a fresh GPT disk with `GptDisk::create()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CreateOptions {
+    /// Disk size in bytes. If None, the current device length is used;
+    /// otherwise shorter devices are extended to this size.
+    pub size: Option<u64>,
+    /// Logical block size.
+    pub lb_size: disk::LogicalBlockSize,
+    /// Disk GUID. If None, a new random one is generated.
+    pub disk_guid: Option<uuid::Uuid>,
+    /// Number of entries in the partition arrays.
+    pub entries: u32,
+    /// Alignment (in bytes) for the start of the usable area, if any.
+    /// It must be a multiple of the logical block size.
+    pub alignment: Option<u64>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            lb_size: disk::DEFAULT_SECTOR_SIZE,
+            disk_guid: None,
+            entries: 128,
+            alignment: None,
+        }
+    }
 }
 
 impl Default for GptConfig {
     fn default() -> Self {
         Self {
             lb_size: disk::DEFAULT_SECTOR_SIZE,
+            lb_size_auto: false,
             initialized: true,
             writable: false,
+            max_array_size: header::DEFAULT_MAX_ARRAY_SIZE,
+            virtual_disk_size: None,
+            skip_backup: false,
+            minimal_writes: false,
+            ignore_corrupt_backup: false,
         }
     }
 }
 
 /// A GPT disk backed by an arbitrary device.
 #[derive(Debug)]
-pub struct GptDisk<'a> {
+pub struct GptDisk<D> {
     config: GptConfig,
-    device: DiskDeviceObject<'a>,
+    device: D,
     guid: uuid::Uuid,
     primary_header: Option<header::Header>,
     backup_header: Option<header::Header>,
     partitions: BTreeMap<u32, partition::Partition>,
+    /// On-disk logical block size, if the table has been converted since.
+    converted_from: Option<disk::LogicalBlockSize>,
+    skipped_sectors: u64,
 }
 
-impl<'a> GptDisk<'a> {
+impl<D: DiskDevice> GptDisk<D> {
+    /// Create a fresh GPT disk on `device`, in a single step.
+    ///
+    /// The device is extended to the requested size if needed, then a
+    /// protective MBR, both headers and zeroed partition arrays are written.
+    /// The returned disk is writable and has no partitions.
+    pub fn create(mut device: D, options: CreateOptions) -> io::Result<Self> {
+        let lb_bytes: u64 = options.lb_size.into();
+        let cur_len = device.seek(io::SeekFrom::End(0))?;
+        let size = options.size.unwrap_or(cur_len);
+        if size % lb_bytes != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("disk size {} is not a multiple of {} bytes sectors", size, lb_bytes),
+            ));
+        }
+        if cur_len > size {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("device is larger ({} bytes) than requested size ({} bytes)", cur_len, size),
+            ));
+        }
+        if cur_len < size {
+            debug!("extending device from {} to {} bytes", cur_len, size);
+            device.seek(io::SeekFrom::Start(size - 1))?;
+            device.write_all(&[0])?;
+        }
+
+        let mut table = table::GptTable::new(options.lb_size, size, options.entries, options.disk_guid)?;
+        if let Some(alignment) = options.alignment {
+            table.align_first_usable(alignment)?;
+        }
+        let mbr = mbr::ProtectiveMBR::with_lb_size(
+            u32::try_from(table.backup_lba()).unwrap_or(0xFF_FF_FF_FF));
+        mbr.overwrite_lba0(&mut device)?;
+
+        let mut gdisk = GptConfig::new()
+            .initialized(false)
+            .writable(true)
+            .logical_block_size(options.lb_size)
+            .create_from_device(device, options.disk_guid)?;
+        gdisk.update_table(table)?;
+        gdisk.write_inplace()?;
+        Ok(gdisk)
+    }
+
     /// Add another partition to this disk.  This tries to find
     /// the optimum partition location with the lowest block device.
     /// Returns the new partition id if there was sufficient room
@@ -228,51 +508,19 @@ impl<'a> GptDisk<'a> {
         part_type: partition_types::Type,
         flags: u64,
     ) -> io::Result<u32> {
-        let size_lba = match size.checked_div(self.config.lb_size.into()) {
-            Some(s) => s,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "size must be greater than {} which is the logical block size.",
-                        self.config.lb_size
-                    ),
-                ));
-            }
-        };
-        // Find the lowest lba that is larger than size.
-        let free_sections = self.find_free_sectors();
-        for (starting_lba, length) in free_sections {
-            debug!("starting_lba {}, length {}", starting_lba, length);
-            if length >= (size_lba - 1) {
-                // Found our free slice.
-                let partition_id = self.find_next_partition_id();
-                debug!(
-                    "Adding partition id: {} {:?}.  first_lba: {} last_lba: {}",
-                    partition_id,
-                    part_type,
-                    starting_lba,
-                    starting_lba + size_lba - 1_u64
-                );
-                let part = partition::Partition {
-                    part_type_guid: part_type,
-                    part_guid: uuid::Uuid::new_v4(),
-                    first_lba: starting_lba,
-                    last_lba: starting_lba + size_lba - 1_u64,
-                    flags,
-                    name: name.to_string(),
-                };
-                if let Some(p) = self.partitions.insert(partition_id, part.clone()) {
-                    debug!("Replacing\n{}\nwith\n{}", p, part);
-                }
-                return Ok(partition_id);
-            }
-        }
-
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to find enough space on drive",
-        ))
+        let (first_usable, last_usable) = self.usable_range().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "Unable to find enough space on drive")
+        })?;
+        table::add_partition(
+            &mut self.partitions,
+            first_usable,
+            last_usable,
+            self.config.lb_size,
+            name,
+            size,
+            part_type,
+            flags,
+        )
     }
     /// remove partition from this disk. This tries to find the partition based on either a
     /// given partition number (id) or a partition guid.  Returns the partition id if the
@@ -282,80 +530,230 @@ impl<'a> GptDisk<'a> {
         id: Option<u32>,
         partguid: Option<uuid::Uuid>,
     ) -> io::Result<u32> {
-        if let Some(part_id) = id {
-            if let Some(partition_id) = self.partitions.remove(&part_id) {
-                debug!("Removing partition number {}", partition_id);
-            }
-            return Ok(part_id);
-        }
-        if let Some(part_guid) = partguid {
-            for (key, partition) in &self.partitions.clone() {
-                if partition.part_guid == part_guid {
-                    if let Some(partition_id) = self.partitions.remove(&key) {
-                        debug!("Removing partition number {}", partition_id);
-                    }
-                    return Ok(*key);
-                }
-            }
-        }
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to find partition to remove",
-        ))
+        table::remove_partition(&mut self.partitions, id, partguid)
     }
 
     /// Find free space on the disk.
     /// Returns a tuple of (starting_lba, length in lba's).
     pub fn find_free_sectors(&self) -> Vec<(u64, u64)> {
-        if let Some(header) = self.primary_header().or_else(|| self.backup_header()) {
-            trace!("first_usable: {}", header.first_usable);
-            let mut disk_positions = vec![header.first_usable];
-            for part in self.partitions().iter().filter(|p| p.1.is_used()) {
-                trace!("partition: ({}, {})", part.1.first_lba, part.1.last_lba);
-                disk_positions.push(part.1.first_lba);
-                disk_positions.push(part.1.last_lba);
+        match self.usable_range() {
+            Some((first_usable, last_usable)) => {
+                table::find_free_sectors(&self.partitions, first_usable, last_usable)
             }
-            disk_positions.push(header.last_usable);
-            trace!("last_usable: {}", header.last_usable);
-            disk_positions.sort_unstable();
-
-            return disk_positions
-                // Walk through the LBA's in chunks of 2 (ending, starting).
-                .chunks(2)
-                // Add 1 to the ending and then subtract the starting if NOT the first usable sector
-                .map(|p| {
-                    if p[0] != header.first_usable {
-                        (p[0] + 1, p[1].saturating_sub(p[0] + 1))
-                    } else {
-                        (p[0], p[1].saturating_sub(p[0]))
-                    }
-                })
-                .collect();
-        }
-        // No primary header. Return nothing.
-        vec![]
+            // No primary header. Return nothing.
+            None => vec![],
+        }
     }
 
     /// Find next highest partition id.
     pub fn find_next_partition_id(&self) -> u32 {
-        let max = match self
-            .partitions()
-            .iter()
-            // Skip unused partitions.
-            .filter(|p| p.1.is_used())
-            // Find the maximum id.
-            .max_by_key(|x| x.0)
-        {
-            Some(i) => *i.0,
-            // Partitions start at 1.
-            None => return 1,
-        };
-        for i in 1..max {
-            if self.partitions().get(&i).is_none() {
-                return i;
-            }
+        table::find_next_partition_id(&self.partitions)
+    }
+
+    fn usable_range(&self) -> Option<(u64, u64)> {
+        self.primary_header()
+            .or_else(|| self.backup_header())
+            .map(|h| (h.first_usable, h.last_usable))
+    }
+
+    /// Return the LBA of the backup header, for the configured virtual
+    /// disk size or otherwise the device length.
+    fn find_backup_lba(&mut self, lb_size: disk::LogicalBlockSize) -> io::Result<u64> {
+        match self.config.virtual_disk_size {
+            Some(size) => header::backup_lba_for_size(size, lb_size),
+            None => header::find_backup_lba(&mut self.device, lb_size),
+        }
+    }
+
+    /// Return the backup region (partition array and header) of this
+    /// disk, as it would be written by `write()`.
+    ///
+    /// The result is a tuple of (bytes offset on disk, content), which
+    /// can be flashed separately when the image is smaller than the
+    /// target device, see `GptConfig::virtual_disk_size`.
+    pub fn backup_region(&mut self) -> io::Result<(u64, Vec<u8>)> {
+        if !self.config.initialized {
+            return Err(io::Error::new(io::ErrorKind::Other, "disk not initialized"));
+        }
+        let bak = self.find_backup_lba(self.config.lb_size)?;
+        let lb_bytes: u64 = self.config.lb_size.into();
+        let mut h2 = header::Header::compute_new(
+            false, &self.partitions, self.guid, bak, &self.backup_header, self.config.lb_size, None)?;
+        h2.crc32_parts = partition::partitions_checksum(&self.partitions, h2.num_parts, h2.part_size)?;
+
+        let offset = h2.part_start.checked_mul(lb_bytes).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "backup region overflow - offset")
+        })?;
+        let mut region = partition::partitions_to_bytes(&self.partitions, h2.num_parts, h2.part_size)?;
+        region.resize(((bak - h2.part_start) * lb_bytes) as usize, 0);
+        let mut hdr = h2.to_bytes()?;
+        hdr.resize(self.config.lb_size.into(), 0);
+        region.extend_from_slice(&hdr);
+        Ok((offset, region))
+    }
+
+    /// Save the partition table of this disk as a backup file, in the
+    /// format written by `sgdisk --backup`.
+    ///
+    /// The file holds the MBR, main header and backup header in 512 bytes
+    /// records, followed by the partition array. Headers are computed
+    /// from the in-memory table, so the backup is complete even if one
+    /// of the on-disk copies is damaged or missing.
+    /// See `GptConfig::restore_backup` to restore it.
+    pub fn save_backup<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let table = self
+            .table()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk not initialized"))?;
+        let mut mbr_bytes = vec![0u8; SGDISK_BACKUP_UNIT];
+        self.device.seek(io::SeekFrom::Start(0))?;
+        self.device.read_exact(&mut mbr_bytes)?;
+        if mbr_bytes[510..] != [0x55, 0xAA] {
+            debug!("no MBR on disk, saving a protective one");
+            let bak = u32::try_from(table.backup_lba()).unwrap_or(0xFF_FF_FF_FF);
+            mbr_bytes = mbr::ProtectiveMBR::with_lb_size(bak).as_bytes()?;
+        }
+        writer.write_all(&mbr_bytes)?;
+        for h in &[table.primary_header()?, table.backup_header()?] {
+            let mut bytes = h.to_bytes()?;
+            bytes.resize(SGDISK_BACKUP_UNIT, 0);
+            writer.write_all(&bytes)?;
         }
-        max + 1
+        writer.write_all(&partition::partitions_to_bytes(
+            table.partitions(),
+            table.num_parts(),
+            table.part_size(),
+        )?)?;
+        writer.flush()
+    }
+
+    /// Return a device bounded to partition `id`, for reading and writing
+    /// its content.
+    ///
+    /// Offsets are relative to the start of the partition, and I/O past
+    /// its end is refused.
+    pub fn partition_device(&mut self, id: u32) -> io::Result<device::PartitionDevice<&mut D>> {
+        let part = self
+            .partitions
+            .get(&id)
+            .filter(|p| p.is_used())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, format!("partition {} not found", id)))?;
+        let start = part.bytes_start(self.config.lb_size)?;
+        let len = part.bytes_len(self.config.lb_size)?;
+        device::PartitionDevice::new(&mut self.device, start, len)
+    }
+
+    /// Open the GPT disk nested inside partition `id`, according to
+    /// `config`, see `partition_device`.
+    pub fn open_nested(
+        &mut self,
+        id: u32,
+        config: GptConfig,
+    ) -> io::Result<GptDisk<device::PartitionDevice<&mut D>>> {
+        let pdev = self.partition_device(id)?;
+        config.open_from_device(pdev)
+    }
+
+    /// Return a device-free copy of this disk partition table, if any.
+    pub fn table(&self) -> Option<table::GptTable> {
+        let header = self.primary_header().or_else(|| self.backup_header())?;
+        let mut t = table::GptTable::from_header(header, self.partitions.clone(), self.config.lb_size);
+        t.update_guid(Some(self.guid));
+        Some(t)
+    }
+
+    /// Serialize this disk partition table as JSON, see `GptTable::to_json`.
+    ///
+    /// Unlike the table export, this also looks at the device: the output
+    /// carries the `disksize` in bytes, and the `pmbr` disk signature and
+    /// partition records as found in LBA0 (or `null` if there is no MBR).
+    pub fn to_json(&mut self) -> io::Result<String> {
+        let table = self
+            .table()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk not initialized"))?;
+        let pmbr = mbr::ProtectiveMBR::from_disk(&mut self.device, self.config.lb_size);
+        if let Err(e) = &pmbr {
+            debug!("no MBR on disk: {}", e);
+        }
+        let cur = self.device.seek(io::SeekFrom::Current(0))?;
+        let disk_size = self.device.seek(io::SeekFrom::End(0))?;
+        self.device.seek(io::SeekFrom::Start(cur))?;
+        table.to_disk_json(pmbr.as_ref().ok(), disk_size)
+    }
+
+    /// Export this disk partition table as (main, backup) flash images
+    /// for a device of `target_size` bytes, see `GptTable::to_flash_images`.
+    pub fn export_flash_images(&self, target_size: u64) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut table = self
+            .table()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk not initialized"))?;
+        table.retarget(target_size)?;
+        table.to_flash_images()
+    }
+
+    /// Replace this disk partition table with `table`, binding it to
+    /// the underlying device.
+    ///
+    /// The table must use the same logical block size as the disk and
+    /// fit within the device.
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn update_table(&mut self, table: table::GptTable) -> io::Result<&Self> {
+        if *table.logical_block_size() != self.config.lb_size {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "table logical block size {} does not match disk ({})",
+                    table.logical_block_size(),
+                    self.config.lb_size
+                ),
+            ));
+        }
+        table.validate()?;
+        let bak = self.find_backup_lba(self.config.lb_size)?;
+        if table.backup_lba() > bak {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "table does not fit in device",
+            ));
+        }
+        let primary = table.primary_header()?;
+        let backup = table.backup_header()?;
+        if table.last_usable() >= backup.part_start {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "table usable area overlaps backup partition array",
+            ));
+        }
+        self.guid = *table.guid();
+        self.primary_header = Some(primary);
+        self.backup_header = Some(backup);
+        self.partitions = table.partitions().clone();
+        self.config.initialized = true;
+        Ok(self)
+    }
+
+    /// Compute the plan turning the partition table of this disk into
+    /// `layout`, see the `layout` module.
+    ///
+    /// Nothing is changed until the plan is passed to `apply_plan()`.
+    pub fn reconcile(&self, layout: &layout::Layout) -> io::Result<layout::Plan> {
+        let table = self
+            .table()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk not initialized"))?;
+        layout.plan(&table)
+    }
+
+    /// Apply a plan computed by `reconcile()`, failing if the partition
+    /// table changed in the meantime.
+    ///
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn apply_plan(&mut self, plan: &layout::Plan) -> io::Result<&Self> {
+        if self.table().as_ref() != Some(plan.base()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "partition table changed since the plan was computed",
+            ));
+        }
+        self.update_table(plan.target().clone())
     }
 
     /// Retrieve primary header, if any.
@@ -383,13 +781,33 @@ impl<'a> GptDisk<'a> {
         &self.config.lb_size
     }
 
+    /// Retrieve the underlying device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Retrieve the underlying device, mutably.
+    ///
+    /// Writing through it directly may make the on-disk state diverge
+    /// from the in-memory headers and partitions.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Consume this disk object, returning the underlying device.
+    ///
+    /// Unlike `write()`, no pending changes are recorded to disk.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
     /// Change the disk device that we are reading/writing from/to.
     /// Returns the previous disk device.
     pub fn update_disk_device(
         &mut self,
-        device: DiskDeviceObject<'a>,
+        device: D,
         writable: bool
-    ) -> DiskDeviceObject {
+    ) -> D {
         self.config.writable = writable;
         std::mem::replace(&mut self.device, device)
     }
@@ -419,7 +837,7 @@ impl<'a> GptDisk<'a> {
         pp: BTreeMap<u32, partition::Partition>,
     ) -> io::Result<&Self> {
         // TODO(lucab): validate partitions.
-        let bak = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
+        let bak = self.find_backup_lba(self.config.lb_size)?;
         let h1 = header::Header::compute_new(
             true, &pp, self.guid, bak, &self.primary_header, self.config.lb_size, None)?;
         let h2 = header::Header::compute_new(
@@ -440,7 +858,7 @@ impl<'a> GptDisk<'a> {
         num_parts: u32,
     ) -> io::Result<&Self> {
         // TODO(lucab): validate partitions.
-        let bak = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
+        let bak = self.find_backup_lba(self.config.lb_size)?;
         let h1 = header::Header::compute_new(
             true, &pp, self.guid, bak, &self.primary_header, self.config.lb_size, Some(num_parts))?;
         let h2 = header::Header::compute_new(
@@ -452,12 +870,88 @@ impl<'a> GptDisk<'a> {
         Ok(self)
     }
 
+    /// Convert the partition table to a different logical block size.
+    ///
+    /// All partition LBAs are rescaled and headers are recomputed for
+    /// the new sector size, keeping the number of partition entries.
+    /// Partitions whose boundaries are not aligned to the new size are
+    /// rejected. On `write()`, stale headers at the old locations are
+    /// wiped and the protective MBR is rewritten to match the new size.
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn convert_block_size(&mut self, new_size: disk::LogicalBlockSize) -> io::Result<&Self> {
+        let old_size = self.config.lb_size;
+        let primary = match self.primary_header.as_ref() {
+            Some(h) => h.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "disk not initialized")),
+        };
+        if new_size == old_size {
+            return Ok(self);
+        }
+        let old_bytes: u64 = old_size.into();
+        let new_bytes: u64 = new_size.into();
+        let to_bytes = |lba: u64| {
+            lba.checked_mul(old_bytes).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "LBA overflow - converting block size")
+            })
+        };
+
+        let mut pp = BTreeMap::new();
+        for (id, part) in &self.partitions {
+            let mut part = part.clone();
+            if part.is_used() {
+                let start = to_bytes(part.first_lba)?;
+                let end = to_bytes(part.last_lba.saturating_add(1))?;
+                if start % new_bytes != 0 || end % new_bytes != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("partition {} is not aligned to {} bytes sectors", id, new_size),
+                    ));
+                }
+                part.first_lba = start / new_bytes;
+                part.last_lba = (end / new_bytes) - 1;
+            }
+            pp.insert(*id, part);
+        }
+
+        // Keep the same usable byte range, clamped to the new metadata areas.
+        let bak = self.find_backup_lba(new_size)?;
+        let original = Some(primary.clone());
+        let mut h1 = header::Header::compute_new(
+            true, &pp, self.guid, bak, &original, new_size, Some(primary.num_parts))?;
+        let mut h2 = header::Header::compute_new(
+            false, &pp, self.guid, bak, &original, new_size, Some(primary.num_parts))?;
+        let first_usable_bytes = to_bytes(primary.first_usable)?;
+        let first_usable = h1.first_usable.max((first_usable_bytes + new_bytes - 1) / new_bytes);
+        let last_usable_end = to_bytes(primary.last_usable.saturating_add(1))?;
+        let last_usable = h1.last_usable.min((last_usable_end / new_bytes).saturating_sub(1));
+        for h in &mut [&mut h1, &mut h2] {
+            h.first_usable = first_usable;
+            h.last_usable = last_usable;
+        }
+        for (id, part) in pp.iter().filter(|p| p.1.is_used()) {
+            if part.first_lba < first_usable || part.last_lba > last_usable {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("partition {} does not fit in usable area after conversion", id),
+                ));
+            }
+        }
+
+        debug!("converting logical block size from {} to {}", old_size, new_size);
+        self.primary_header = Some(h1);
+        self.backup_header = Some(h2);
+        self.partitions = pp;
+        self.config.lb_size = new_size;
+        self.converted_from.get_or_insert(old_size);
+        Ok(self)
+    }
+
     /// Persist state to disk, consuming this disk object.
     ///
     /// This is a destructive action, as it overwrite headers and
     /// partitions entries on disk. All writes are flushed to disk
-    /// before returning the underlying DiskDeviceObject.
-    pub fn write(mut self) -> io::Result<DiskDeviceObject<'a>> {
+    /// before returning the underlying device.
+    pub fn write(mut self) -> io::Result<D> {
         self.write_inplace()?;
         Ok(self.device)
     }
@@ -480,78 +974,31 @@ impl<'a> GptDisk<'a> {
         debug!("Computing new headers");
         trace!("old primary header: {:?}", self.primary_header);
         trace!("old backup header: {:?}", self.backup_header);
-        let bak = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
+        let bak = self.find_backup_lba(self.config.lb_size)?;
         trace!("old backup lba: {}", bak);
-        let primary_header = self.primary_header.clone().unwrap();
-        let backup_header = self.backup_header.clone();
-
-        // Write all of the used partitions at the start of the partition array.
-        let mut next_partition_index = 0u64;
-        for partition in self.partitions().clone().iter().filter(|p| p.1.is_used()) {
-            // don't allow us to overflow partition array...
-            if next_partition_index >= u64::from(primary_header.num_parts) {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("attempting to write more than max of {} partitions in primary array",
-                        primary_header.num_parts),
-                ));
-            }
+        if self.primary_header.is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "disk not initialized"));
+        }
+        let backup_header = if self.config.skip_backup {
+            None
+        } else {
+            self.backup_header.clone()
+        };
 
-            // Write to primary partition array
-            partition.1.write_to_device(
-                &mut self.device,
-                next_partition_index,
-                primary_header.part_start,
-                self.config.lb_size,
-                primary_header.part_size,
-            )?;
-            // IMPORTANT: must also write it to the backup header if it uses a different
-            // area to store the partition array; otherwise backup header will not point
-            // to an up to date partition array on disk.
-            if let Some(backup_header) = backup_header.as_ref() {
-                if next_partition_index >= u64::from(backup_header.num_parts) {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("attempting to write more than max of {} partitions in backup array",
-                            backup_header.num_parts),
-                    ));
-                }
-                if primary_header.part_start != backup_header.part_start {
-                    partition.1.write_to_device(
-                        &mut self.device,
-                        next_partition_index,
-                        backup_header.part_start,
-                        self.config.lb_size,
-                        backup_header.part_size,
-                    )?;
-                }
+        self.skipped_sectors = 0;
+        let lb_bytes: u64 = self.config.lb_size.into();
+
+        // Wipe headers left at their locations before a block size conversion.
+        if let Some(old_size) = self.converted_from {
+            let old_bytes: u64 = old_size.into();
+            let old_bak = self.find_backup_lba(old_size)?;
+            for lba in &[1, old_bak] {
+                trace!("wiping stale header at {} bytes", lba * old_bytes);
+                self.write_sectors(lba * old_bytes, &vec![0u8; old_size.into()])?;
             }
-            next_partition_index += 1;
         }
 
-        // Next, write zeros to the rest of the primary/backup partition array
-        // (ensures any newly deleted partitions are truly removed from disk, etc.)
-        // NOTE: we should never underflow here because of boundary checking in loop above.
-        partition::Partition::write_zero_entries_to_device(
-            &mut self.device,
-            next_partition_index,
-            u64::from(primary_header.num_parts).checked_sub(next_partition_index).unwrap(),
-            primary_header.part_start,
-            self.config.lb_size,
-            primary_header.part_size,
-        )?;
-        if let Some(backup_header) = backup_header.as_ref() {
-            partition::Partition::write_zero_entries_to_device(
-                &mut self.device,
-                next_partition_index,
-                u64::from(backup_header.num_parts).checked_sub(next_partition_index).unwrap(),
-                backup_header.part_start,
-                self.config.lb_size,
-                backup_header.part_size,
-            )?;
-        }
-
-        let new_backup_header = header::Header::compute_new(
+        let mut new_backup_header = header::Header::compute_new(
             false,
             &self.partitions,
             self.guid,
@@ -560,26 +1007,126 @@ impl<'a> GptDisk<'a> {
             self.config.lb_size,
             None,
         )?;
-        let new_primary_header = header::Header::compute_new(
+        let mut new_primary_header = header::Header::compute_new(
             true,
             &self.partitions,
             self.guid,
             bak,
-            &self.backup_header,
+            &backup_header.clone().or_else(|| self.primary_header.clone()),
             self.config.lb_size,
             None,
         )?;
-        debug!("Writing backup header");
-        new_backup_header.write_backup(&mut self.device, self.config.lb_size)?;
-        debug!("Writing primary header");
-        new_primary_header.write_primary(&mut self.device, self.config.lb_size)?;
+        // Checksums are computed from memory, matching the arrays written below.
+        for h in &mut [&mut new_backup_header, &mut new_primary_header] {
+            h.crc32_parts = partition::partitions_checksum(&self.partitions, h.num_parts, h.part_size)?;
+            h.crc32 = h.checksum()?;
+        }
+
+        // The backup copy is fully written before the primary one, so that
+        // an interrupted write always leaves at least one consistent copy:
+        // either the old primary, or the new backup.
+        let mut copies = vec![&new_primary_header];
+        if !self.config.skip_backup {
+            copies.insert(0, &new_backup_header);
+        }
+        let mut arrays_written = Vec::with_capacity(2);
+        for h in copies {
+            // Write the whole partition array, with unused entries zeroed
+            // (ensures any newly deleted partitions are truly removed from disk, etc.)
+            if !arrays_written.contains(&h.part_start) {
+                let mut array = partition::partitions_to_bytes(&self.partitions, h.num_parts, h.part_size)?;
+                array.resize(((array.len() as u64 + lb_bytes - 1) / lb_bytes * lb_bytes) as usize, 0);
+                self.write_sectors(h.part_start * lb_bytes, &array)?;
+                arrays_written.push(h.part_start);
+            }
+            debug!("Writing header at LBA {}", h.current_lba);
+            let mut bytes = h.to_bytes()?;
+            // Per the spec, the rest of the logical block must be zeros...
+            bytes.resize(self.config.lb_size.into(), 0x00);
+            self.write_sectors(h.current_lba * lb_bytes, &bytes)?;
+        }
         trace!("new primary header: {:?}", new_primary_header);
         trace!("new backup header: {:?}", new_backup_header);
+        if self.converted_from.is_some() || self.config.skip_backup {
+            debug!("Rewriting protective MBR");
+            // Only the partition records and signature change: the boot code
+            // and disk signature read back from LBA0 are kept. Unlike
+            // `ProtectiveMBR::update_conservative`, the whole first logical
+            // block is rewritten, so that writes stay sector-aligned.
+            let pmbr = mbr::ProtectiveMBR::with_lb_size(u32::try_from(bak).unwrap_or(0xFF_FF_FF_FF));
+            let mut lba0 = vec![0u8; lb_bytes as usize];
+            self.device.seek(io::SeekFrom::Start(0))?;
+            self.device.read_exact(&mut lba0)?;
+            lba0[446..512].copy_from_slice(&pmbr.as_bytes()?[446..]);
+            self.write_sectors(0, &lba0)?;
+            self.converted_from = None;
+        }
 
+        if self.skipped_sectors > 0 {
+            debug!("skipped writing {} unchanged sectors", self.skipped_sectors);
+        }
         self.device.flush()?;
         self.primary_header = Some(new_primary_header);
-        self.backup_header = Some(new_backup_header);
+        if !self.config.skip_backup {
+            self.backup_header = Some(new_backup_header);
+        }
 
         Ok(())
     }
+
+    /// Return the number of unchanged sectors that the last write
+    /// skipped, see `GptConfig::minimal_writes`.
+    pub fn skipped_sectors(&self) -> u64 {
+        self.skipped_sectors
+    }
+
+    /// Write `buf` at `offset`, skipping unchanged sectors in
+    /// minimal-write mode.
+    fn write_sectors(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if !self.config.minimal_writes {
+            self.device.seek(io::SeekFrom::Start(offset))?;
+            return self.device.write_all(buf);
+        }
+
+        // Read back current content, which may be short at end of device.
+        let mut current = vec![0u8; buf.len()];
+        let mut len = 0;
+        self.device.seek(io::SeekFrom::Start(offset))?;
+        while len < current.len() {
+            match self.device.read(&mut current[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let lb_bytes: usize = self.config.lb_size.into();
+        for (i, sector) in buf.chunks(lb_bytes).enumerate() {
+            let start = i * lb_bytes;
+            let end = start + sector.len();
+            if end <= len && current[start..end] == *sector {
+                self.skipped_sectors += 1;
+                continue;
+            }
+            trace!("writing changed sector at {} bytes", offset + start as u64);
+            self.device.seek(io::SeekFrom::Start(offset + start as u64))?;
+            self.device.write_all(sector)?;
+        }
+        Ok(())
+    }
+
+    /// Write the backup header and partition array, e.g. on the target
+    /// device of an image written in primary-only mode.
+    ///
+    /// The backup is placed at the end of the device (or of the virtual
+    /// disk size, if configured), and the primary header is updated
+    /// to point at it. All writes are flushed to disk before returning.
+    pub fn complete_backup(&mut self) -> io::Result<()> {
+        let skip_backup = self.config.skip_backup;
+        self.config.skip_backup = false;
+        let res = self.write_inplace();
+        self.config.skip_backup = skip_backup;
+        res
+    }
 }
@@ -44,6 +44,13 @@ pub mod pub_macros {
                 }
             }
         }
+
+        /// Names of all known partition types, with their type-GUID.
+        const TYPE_NAMES: &[(&str, &str)] = &[
+            $(
+                (stringify!($upcase), $guid),
+            )+
+        ];
     }
 }
 }
\ No newline at end of file
@@ -15,10 +15,13 @@ use std::path::Path;
 use std::str::FromStr;
 
 use crate::disk;
-use crate::header::{parse_uuid, Header};
+use crate::header::{parse_uuid, Header, DEFAULT_MAX_ARRAY_SIZE};
 use crate::partition_types::Type;
 use crate::DiskDevice;
 
+/// Size (in bytes) of the fields of a partition entry.
+pub const ENTRY_SIZE: usize = 128;
+
 bitflags! {
     /// Partition entry attributes, defined for UEFI.
     pub struct PartitionAttributes: u64 {
@@ -61,8 +64,35 @@ impl Partition {
         }
     }
 
-    /// Serialize this partition entry to its bytes representation.
-    fn as_bytes(&self, entry_size: u32) -> Result<Vec<u8>> {
+    /// Parse a partition entry from its on-disk byte representation.
+    ///
+    /// The buffer must hold at least the 128 bytes of a partition entry;
+    /// any trailing bytes (for larger entry sizes) are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < ENTRY_SIZE {
+            return Err(Error::new(ErrorKind::Other, "invalid partition entry length"));
+        }
+        let mut reader = Cursor::new(&buf[..56]);
+        let type_guid = parse_uuid(&mut reader)?;
+        let part_guid = parse_uuid(&mut reader)?;
+        let partname = read_part_name(&mut Cursor::new(&buf[56..ENTRY_SIZE]))?;
+        let p = Partition {
+            part_type_guid: Type::from_uuid_or_raw(&type_guid),
+            part_guid,
+            first_lba: u64::from_le_bytes(read_exact_buff!(flba, reader, 8)),
+            last_lba: u64::from_le_bytes(read_exact_buff!(llba, reader, 8)),
+            flags: u64::from_le_bytes(read_exact_buff!(flagbuff, reader, 8)),
+            name: partname,
+        };
+        Ok(p)
+    }
+
+    /// Serialize this partition entry to its on-disk byte representation,
+    /// zero-padded to `entry_size` bytes (usually 128).
+    pub fn to_bytes(&self, entry_size: u32) -> Result<Vec<u8>> {
+        if usize::try_from(entry_size).ok().map_or(true, |size| size < ENTRY_SIZE) {
+            return Err(Error::new(ErrorKind::Other, "invalid partition entry size"));
+        }
         let mut buf: Vec<u8> = Vec::with_capacity(entry_size as usize);
 
         // Type GUID.
@@ -131,8 +161,9 @@ impl Partition {
             .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow"))?;
         trace!("seeking to partition start: {}", pstart + offset);
         device.seek(SeekFrom::Start(pstart + offset))?;
-        trace!("writing {:?}", &self.as_bytes(bytes_per_partition));
-        device.write_all(&self.as_bytes(bytes_per_partition)?)?;
+        let bytes = self.to_bytes(bytes_per_partition)?;
+        trace!("writing {:?}", &bytes);
+        device.write_all(&bytes)?;
 
         Ok(())
     }
@@ -166,11 +197,15 @@ impl Partition {
     }
 
     /// Return the length (in bytes) of this partition.
+    ///
+    /// Both `first_lba` and `last_lba` are included in the partition.
     pub fn bytes_len(&self, lb_size: disk::LogicalBlockSize) -> Result<u64> {
         let len = self
             .last_lba
             .checked_sub(self.first_lba)
             .ok_or_else(|| Error::new(ErrorKind::Other, "partition length underflow - sectors"))?
+            .checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::Other, "partition length overflow - sectors"))?
             .checked_mul(lb_size.into())
             .ok_or_else(|| Error::new(ErrorKind::Other, "partition length overflow - bytes"))?;
         Ok(len)
@@ -258,69 +293,118 @@ pub fn read_partitions(
 }
 
 /// Read a GPT partition table from an open `Read` + `Seek` object.
+///
+/// The header is validated first, with a partition entry array of at
+/// most `header::DEFAULT_MAX_ARRAY_SIZE` bytes.
 pub fn file_read_partitions<D: Read + Seek>(
     file: &mut D,
     header: &Header,
     lb_size: disk::LogicalBlockSize,
 ) -> Result<BTreeMap<u32, Partition>> {
+    file_read_partitions_bounded(file, header, lb_size, DEFAULT_MAX_ARRAY_SIZE)
+}
+
+/// Read a GPT partition table from an open `Read` + `Seek` object,
+/// refusing partition entry arrays larger than `max_array_size` bytes.
+pub fn file_read_partitions_bounded<D: Read + Seek>(
+    file: &mut D,
+    header: &Header,
+    lb_size: disk::LogicalBlockSize,
+    max_array_size: u64,
+) -> Result<BTreeMap<u32, Partition>> {
+    header.validate(lb_size, max_array_size)?;
     let pstart = header
         .part_start
         .checked_mul(lb_size.into())
         .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow - start offset"))?;
-    trace!("seeking to partitions start: {:#x}", pstart);
-    let _ = file.seek(SeekFrom::Start(pstart))?;
-    let mut parts: BTreeMap<u32, Partition> = BTreeMap::new();
-
-    trace!("scanning {} partitions", header.num_parts);
-    let mut count = 0;
-    for i in 0..header.num_parts {
-        let mut bytes: [u8; 56] = [0; 56];
-        let mut nameraw: [u8; 72] = [0; 72];
-
-        file.read_exact(&mut bytes)?;
-        file.read_exact(&mut nameraw)?;
-
-        let test: [u8; 56] = [0; 56];
-        let test2: [u8; 72] = [0; 72];
-        // Note: unused partition entries are zeroed, so skip them
-        if bytes.eq(&test[0..]) && nameraw.eq(&test2[0..]) {
-            count += 1;
-        } else {
-            let mut reader = Cursor::new(&bytes[..]);
-            let type_guid = parse_uuid(&mut reader)?;
-            let part_guid = parse_uuid(&mut reader)?;
-
-            let partname = read_part_name(&mut Cursor::new(&nameraw[..]))?;
-            let p = Partition {
-                part_type_guid: Type::from_uuid(&type_guid).unwrap_or_default(),
-                part_guid,
-                first_lba: u64::from_le_bytes(read_exact_buff!(flba, reader, 8)),
-                last_lba: u64::from_le_bytes(read_exact_buff!(llba, reader, 8)),
-                flags: u64::from_le_bytes(read_exact_buff!(flagbuff, reader, 8)),
-                name: partname.to_string(),
-            };
-
-            parts.insert(i + 1, p);
-        }
-    }
-    debug!("Num Zeroed partitions {:?}\n\n", count);
-
-    debug!("checking partition table CRC");
+    trace!("reading partition array at {:#x}", pstart);
     let _ = file.seek(SeekFrom::Start(pstart))?;
-    let pt_len = u64::from(header.num_parts)
-        .checked_mul(header.part_size.into())
-        .ok_or_else(|| Error::new(ErrorKind::Other, "partitions - size"))?;
-    let mut table = vec![0; pt_len as usize];
+    let pt_len = usize::try_from(header.array_size()?)
+        .map_err(|_| Error::new(ErrorKind::Other, "partitions - size"))?;
+    let mut table = vec![0; pt_len];
     file.read_exact(&mut table)?;
 
+    debug!("checking partition table CRC");
     let comp_crc = crc32::checksum_ieee(&table);
     if comp_crc != header.crc32_parts {
         return Err(Error::new(ErrorKind::Other, "partition table CRC mismatch"));
     }
 
+    trace!("scanning {} partitions", header.num_parts);
+    let parts = partitions_from_bytes(&table, header.num_parts, header.part_size)?;
+    debug!("Num Zeroed partitions {:?}\n\n", header.num_parts as usize - parts.len());
+    Ok(parts)
+}
+
+/// Serialize a partition table to the bytes of a partition entry array.
+///
+/// Each used partition is written at the entry matching its 1-based id,
+/// and all other entries up to `num_parts` are zeroed. This is the inverse
+/// of `partitions_from_bytes`, and the layout that `GptDisk::write()` puts
+/// on disk.
+pub fn partitions_to_bytes(
+    pp: &BTreeMap<u32, Partition>,
+    num_parts: u32,
+    part_size: u32,
+) -> Result<Vec<u8>> {
+    let total_len = u64::from(num_parts)
+        .checked_mul(part_size.into())
+        .and_then(|x| usize::try_from(x).ok())
+        .ok_or_else(|| Error::new(ErrorKind::Other, "partition array overflow - size"))?;
+    let mut buf = vec![0x00; total_len];
+    for (id, part) in pp.iter().filter(|p| p.1.is_used()) {
+        if *id == 0 || *id > num_parts {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("partition {} does not fit in array of {} entries", id, num_parts),
+            ));
+        }
+        let offset = (*id as usize - 1) * part_size as usize;
+        buf[offset..offset + part_size as usize].copy_from_slice(&part.to_bytes(part_size)?);
+    }
+    Ok(buf)
+}
+
+/// Parse a partition entry array, returning used entries keyed by
+/// their 1-based index in the array.
+///
+/// The CRC32 of the array is not checked here, see `partitions_checksum`.
+pub fn partitions_from_bytes(
+    buf: &[u8],
+    num_parts: u32,
+    part_size: u32,
+) -> Result<BTreeMap<u32, Partition>> {
+    let entry_len = usize::try_from(part_size)
+        .ok()
+        .filter(|size| *size >= ENTRY_SIZE)
+        .ok_or_else(|| Error::new(ErrorKind::Other, "invalid partition entry size"))?;
+    let total_len = entry_len
+        .checked_mul(num_parts as usize)
+        .ok_or_else(|| Error::new(ErrorKind::Other, "partition array overflow - size"))?;
+    if buf.len() < total_len {
+        return Err(Error::new(ErrorKind::Other, "partition array - truncated buffer"));
+    }
+    let mut parts = BTreeMap::new();
+    for (i, entry) in buf[..total_len].chunks(entry_len).enumerate() {
+        // Note: unused partition entries are zeroed, so skip them
+        if entry.iter().any(|b| *b != 0) {
+            parts.insert(i as u32 + 1, Partition::from_bytes(entry)?);
+        }
+    }
     Ok(parts)
 }
 
+/// Compute the CRC32 of the partition entry array for a partition table,
+/// as serialized by `partitions_to_bytes`.
+pub fn partitions_checksum(
+    pp: &BTreeMap<u32, Partition>,
+    num_parts: u32,
+    part_size: u32,
+) -> Result<u32> {
+    let buf = partitions_to_bytes(pp, num_parts, part_size)?;
+    Ok(crc32::checksum_ieee(&buf))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::disk;
@@ -330,11 +414,11 @@ mod tests {
     fn test_zero_part() {
         let p0 = partition::Partition::zero();
 
-        let b128 = p0.as_bytes(128).unwrap();
+        let b128 = p0.to_bytes(128).unwrap();
         assert_eq!(b128.len(), 128);
         assert_eq!(b128, vec![0u8; 128]);
 
-        let b256 = p0.as_bytes(256).unwrap();
+        let b256 = p0.to_bytes(256).unwrap();
         assert_eq!(b256.len(), 256);
         assert_eq!(b256, vec![0u8; 256]);
     }
@@ -342,13 +426,13 @@ mod tests {
     #[test]
     fn test_part_bytes_len() {
         {
-            // Zero.
+            // Zero, i.e. a single sector at LBA 0.
             let p0 = partition::Partition::zero();
             let b512len = p0.bytes_len(disk::LogicalBlockSize::Lb512).unwrap();
             let b4096len = p0.bytes_len(disk::LogicalBlockSize::Lb4096).unwrap();
 
-            assert_eq!(b512len, 0);
-            assert_eq!(b4096len, 0);
+            assert_eq!(b512len, 512);
+            assert_eq!(b4096len, 4096);
         }
 
         {
@@ -375,8 +459,8 @@ mod tests {
             let b512len = p3.bytes_len(disk::LogicalBlockSize::Lb512).unwrap();
             let b4096len = p3.bytes_len(disk::LogicalBlockSize::Lb4096).unwrap();
 
-            assert_eq!(b512len, 2 * 512);
-            assert_eq!(b4096len, 2 * 4096);
+            assert_eq!(b512len, 3 * 512);
+            assert_eq!(b4096len, 3 * 4096);
         }
     }
 
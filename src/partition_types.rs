@@ -104,6 +104,24 @@ fn test_partition_from_name() {
     assert_eq!(t, LINUX_FS);
 }
 
+#[test]
+fn test_partition_name() {
+    assert_eq!(LINUX_FS.name(), Some("LINUX_FS"));
+    assert_eq!(Type::from_name(EFI.name().unwrap()).unwrap(), EFI);
+    let custom = Type { guid: "01234567-89AB-CDEF-0123-456789ABCDEF", os: OperatingSystem::None };
+    assert_eq!(custom.name(), None);
+}
+
+#[test]
+fn test_partition_from_uuid_or_raw() {
+    let known = uuid::Uuid::parse_str("0FC63DAF-8483-4772-8E79-3D69D8477DE4").unwrap();
+    assert_eq!(Type::from_uuid_or_raw(&known), LINUX_FS);
+    let raw = uuid::Uuid::parse_str("01234567-89ab-cdef-0123-456789abcdef").unwrap();
+    let t = Type::from_uuid_or_raw(&raw);
+    assert_eq!(t.guid, "01234567-89AB-CDEF-0123-456789ABCDEF");
+    assert_eq!(t.os, OperatingSystem::None);
+}
+
 impl Type {
     /// Lookup a partition type by uuid
     pub fn from_uuid(u: &uuid::Uuid) -> Result<Self, String> {
@@ -112,12 +130,33 @@ impl Type {
         Type::from_str(&uuid_str)
     }
 
+    /// Return the partition type for a type-GUID, known or not.
+    ///
+    /// Unknown type-GUIDs are kept as-is, with no associated OS. As `guid`
+    /// is a static string, theirs is leaked: this is meant for the bounded
+    /// number of types found in partition tables.
+    pub fn from_uuid_or_raw(u: &uuid::Uuid) -> Self {
+        Type::from_uuid(u).unwrap_or_else(|_| {
+            let guid = u.to_hyphenated().to_string().to_uppercase();
+            Type { guid: Box::leak(guid.into_boxed_str()), os: OperatingSystem::None }
+        })
+    }
+
     /// Lookup a partition type by name
     pub fn from_name(name: &str) -> Result<Self, String> {
         let name_str = name.to_uppercase();
         trace!("looking up partition type by name {}", name_str);
         Type::from_str(&name_str)
     }
+
+    /// Return the name of this partition type (e.g. `LINUX_FS`), as
+    /// accepted by `from_name`, if its type-GUID is a known one.
+    pub fn name(&self) -> Option<&'static str> {
+        TYPE_NAMES
+            .iter()
+            .find(|(_, guid)| guid.eq_ignore_ascii_case(self.guid))
+            .map(|(name, _)| *name)
+    }
 }
 
 impl Default for Type {
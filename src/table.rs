@@ -0,0 +1,543 @@
+//! Device-free partition table and layout helpers.
+//!
+//! A `GptTable` holds the header parameters and partition entries of a
+//! GPT disk, without any device attached. Layouts can be computed, compared
+//! and modified offline, then bound to a device with `GptDisk::update_table()`.
+//!
+//! Tables are imported from and exported to other tools' formats with
+//! `GptTable` methods; formats with their own types have a submodule.
+
+use log::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+
+use crate::disk;
+use crate::header::{Header, DEFAULT_MAX_ARRAY_SIZE, HEADER_SIZE};
+use crate::mbr;
+use crate::partition::{self, Partition};
+use crate::partition_types;
+
+mod json;
+pub mod qualcomm;
+pub mod repart;
+mod sfdisk;
+mod uboot;
+
+/// A GPT partition table, detached from any device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GptTable {
+    lb_size: disk::LogicalBlockSize,
+    disk_guid: uuid::Uuid,
+    num_parts: u32,
+    part_size: u32,
+    first_usable: u64,
+    last_usable: u64,
+    backup_lba: u64,
+    partitions: BTreeMap<u32, Partition>,
+}
+
+impl GptTable {
+    /// Create an empty table for a disk of `disk_size` bytes, with room
+    /// for `num_parts` partition entries.
+    ///
+    /// Partition arrays are placed right after the primary header and
+    /// right before the backup header, which sits in the last LBA.
+    /// If guid is None then it will generate a new random guid.
+    pub fn new(
+        lb_size: disk::LogicalBlockSize,
+        disk_size: u64,
+        num_parts: u32,
+        guid: Option<uuid::Uuid>,
+    ) -> io::Result<Self> {
+        let num_lbs = disk_size / Into::<u64>::into(lb_size);
+        let backup_lba = num_lbs
+            .checked_sub(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk too small for a GPT"))?;
+        let guid = guid.unwrap_or_else(uuid::Uuid::new_v4);
+        let h = Header::compute_new(
+            true, &BTreeMap::new(), guid, backup_lba, &None, lb_size, Some(num_parts))?;
+        if h.first_usable > h.last_usable {
+            return Err(io::Error::new(io::ErrorKind::Other, "disk too small for a GPT"));
+        }
+        Ok(Self::from_header(&h, BTreeMap::new(), lb_size))
+    }
+
+    /// Build a table from a (primary or backup) header and its partitions.
+    pub fn from_header(
+        header: &Header,
+        partitions: BTreeMap<u32, Partition>,
+        lb_size: disk::LogicalBlockSize,
+    ) -> Self {
+        Self {
+            lb_size,
+            disk_guid: header.disk_guid,
+            num_parts: header.num_parts,
+            part_size: header.part_size,
+            first_usable: header.first_usable,
+            last_usable: header.last_usable,
+            backup_lba: header.current_lba.max(header.backup_lba),
+            partitions,
+        }
+    }
+
+    /// Round the first usable LBA up to a multiple of `alignment` bytes,
+    /// which must itself be a non-zero multiple of the logical block size.
+    pub(crate) fn align_first_usable(&mut self, alignment: u64) -> io::Result<()> {
+        let lb_bytes: u64 = self.lb_size.into();
+        if alignment == 0 || alignment % lb_bytes != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("alignment {} is not a multiple of {} bytes sectors", alignment, lb_bytes),
+            ));
+        }
+        let align_lbs = alignment / lb_bytes;
+        let aligned = self
+            .first_usable
+            .checked_add(align_lbs - 1)
+            .map(|x| x / align_lbs * align_lbs)
+            .filter(|x| *x <= self.last_usable)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "disk too small for requested alignment")
+            })?;
+        self.first_usable = aligned;
+        Ok(())
+    }
+
+    /// Compute the primary header for this table, including checksums.
+    pub fn primary_header(&self) -> io::Result<Header> {
+        self.header(1, self.backup_lba, 2)
+    }
+
+    /// Compute the backup header for this table, including checksums.
+    ///
+    /// Its partition array is placed right before the backup header.
+    pub fn backup_header(&self) -> io::Result<Header> {
+        let part_start = self
+            .backup_lba
+            .checked_sub(self.array_lbs()?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "header underflow - backup array"))?;
+        self.header(self.backup_lba, 1, part_start)
+    }
+
+    fn header(&self, current_lba: u64, backup_lba: u64, part_start: u64) -> io::Result<Header> {
+        let mut h = Header {
+            signature: "EFI PART".to_string(),
+            revision: 65536,
+            header_size_le: 92,
+            crc32: 0,
+            reserved: 0,
+            current_lba,
+            backup_lba,
+            first_usable: self.first_usable,
+            last_usable: self.last_usable,
+            disk_guid: self.disk_guid,
+            part_start,
+            num_parts: self.num_parts,
+            part_size: self.part_size,
+            crc32_parts: 0,
+        };
+        h.crc32_parts = partition::partitions_checksum(&self.partitions, self.num_parts, self.part_size)?;
+        h.crc32 = h.checksum()?;
+        Ok(h)
+    }
+
+    /// Return the number of LBAs taken by a partition array.
+    pub fn array_lbs(&self) -> io::Result<u64> {
+        let lb_bytes: u64 = self.lb_size.into();
+        let array_bytes = u64::from(self.num_parts)
+            .checked_mul(self.part_size.into())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "partition array overflow - size"))?;
+        Ok((array_bytes + lb_bytes - 1) / lb_bytes)
+    }
+
+    /// Move the backup GPT to the last LBA of a disk of `disk_size` bytes,
+    /// growing or shrinking the usable area to match.
+    pub fn retarget(&mut self, disk_size: u64) -> io::Result<&Self> {
+        let mut resized = Self::new(self.lb_size, disk_size, self.num_parts, Some(self.disk_guid))?;
+        resized.part_size = self.part_size;
+        let array_lbs = self.array_lbs()?;
+        resized.last_usable = resized
+            .backup_lba
+            .checked_sub(array_lbs + 1)
+            .filter(|last| *last >= self.first_usable)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk too small for partition table"))?;
+        resized.first_usable = self.first_usable;
+        resized.partitions = self.partitions.clone();
+        resized.validate()?;
+        *self = resized;
+        Ok(self)
+    }
+
+    /// Serialize this table as flash images, as expected by fastboot and
+    /// similar tools.
+    ///
+    /// Returns a tuple of (main, backup) buffers: the main image holds the
+    /// protective MBR, primary header and partition array, and is flashed
+    /// at the start of the disk; the backup image holds the backup array
+    /// and header, and is flashed at its end.
+    pub fn to_flash_images(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let lb_bytes: usize = self.lb_size.into();
+        let mut array = partition::partitions_to_bytes(&self.partitions, self.num_parts, self.part_size)?;
+        array.resize((self.array_lbs()? as usize) * lb_bytes, 0);
+
+        let pmbr = mbr::ProtectiveMBR::with_lb_size(u32::try_from(self.backup_lba).unwrap_or(0xFF_FF_FF_FF));
+        let mut main = pmbr.as_bytes()?;
+        main.resize(lb_bytes, 0);
+        main.extend_from_slice(&self.primary_header()?.to_bytes()?);
+        main.resize(2 * lb_bytes, 0);
+        main.extend_from_slice(&array);
+
+        let mut backup = array;
+        backup.extend_from_slice(&self.backup_header()?.to_bytes()?);
+        backup.resize(backup.len() - HEADER_SIZE + lb_bytes, 0);
+        Ok((main, backup))
+    }
+
+    /// Reassemble a table from flash images, see `to_flash_images`.
+    ///
+    /// Both headers and partition arrays are verified, and must agree.
+    pub fn from_flash_images(
+        main: &[u8],
+        backup: &[u8],
+        lb_size: disk::LogicalBlockSize,
+    ) -> io::Result<Self> {
+        let lb_bytes: usize = lb_size.into();
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::Other, format!("invalid flash image: {}", msg));
+        if main.len() < 2 * lb_bytes || backup.len() < lb_bytes {
+            return Err(invalid("too short"));
+        }
+        let h1 = Header::from_bytes(&main[lb_bytes..2 * lb_bytes])?;
+        let h2 = Header::from_bytes(&backup[backup.len() - lb_bytes..])?;
+        for h in &[&h1, &h2] {
+            h.validate(lb_size, DEFAULT_MAX_ARRAY_SIZE)?;
+        }
+        if h1.current_lba != 1 || h1.part_start != 2 {
+            return Err(invalid("unexpected primary header location"));
+        }
+        if h1.disk_guid != h2.disk_guid
+            || h1.num_parts != h2.num_parts
+            || h1.part_size != h2.part_size
+            || h1.crc32_parts != h2.crc32_parts
+            || h1.backup_lba != h2.current_lba
+        {
+            return Err(invalid("primary and backup headers do not match"));
+        }
+
+        // Arrays start right after the primary header, and end right before the backup one.
+        let array_len = h1.array_size()? as usize;
+        let primary_array = main
+            .get(2 * lb_bytes..2 * lb_bytes + array_len)
+            .ok_or_else(|| invalid("truncated primary array"))?;
+        let backup_array_lbs = h2.current_lba.saturating_sub(h2.part_start) as usize;
+        let backup_array = backup
+            .len()
+            .checked_sub(lb_bytes * (1 + backup_array_lbs))
+            .and_then(|start| backup.get(start..start + array_len))
+            .ok_or_else(|| invalid("truncated backup array"))?;
+        for array in &[primary_array, backup_array] {
+            if crc::crc32::checksum_ieee(array) != h1.crc32_parts {
+                return Err(invalid("partition array checksum mismatch"));
+            }
+        }
+
+        let partitions = partition::partitions_from_bytes(primary_array, h1.num_parts, h1.part_size)?;
+        let table = Self::from_header(&h1, partitions, lb_size);
+        table.validate()?;
+        Ok(table)
+    }
+
+    /// Check that partitions fit in the array and in the usable area,
+    /// without overlapping each other.
+    ///
+    /// The partition array must be non-empty and at most
+    /// `header::DEFAULT_MAX_ARRAY_SIZE` bytes, and the usable area must
+    /// not overlap either copy of it.
+    pub fn validate(&self) -> io::Result<()> {
+        let array_bytes = u64::from(self.num_parts) * u64::from(self.part_size);
+        if array_bytes == 0 || array_bytes > DEFAULT_MAX_ARRAY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("invalid partition array size of {} bytes", array_bytes),
+            ));
+        }
+        let array_lbs = self.array_lbs()?;
+        if self.first_usable < 2 + array_lbs {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("first usable LBA {} overlaps primary partition array", self.first_usable),
+            ));
+        }
+        if self.backup_lba < array_lbs || self.last_usable >= self.backup_lba - array_lbs {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("last usable LBA {} overlaps backup partition array", self.last_usable),
+            ));
+        }
+        let used: Vec<(&u32, &Partition)> = self.partitions.iter().filter(|p| p.1.is_used()).collect();
+        if used.len() > self.num_parts as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} partitions do not fit in array of {} entries", used.len(), self.num_parts),
+            ));
+        }
+        let mut spans = Vec::with_capacity(used.len());
+        for (id, part) in used {
+            if part.first_lba > part.last_lba
+                || part.first_lba < self.first_usable
+                || part.last_lba > self.last_usable
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("partition {} is outside of the usable area", id),
+                ));
+            }
+            spans.push((part.first_lba, part.last_lba, *id));
+        }
+        spans.sort_unstable();
+        for pair in spans.windows(2) {
+            if pair[1].0 <= pair[0].1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("partitions {} and {} overlap", pair[0].2, pair[1].2),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add another partition to this table.  This tries to find
+    /// the optimum partition location with the lowest block device.
+    /// Returns the new partition id if there was sufficient room
+    /// to add the partition. Size is specified in bytes.
+    pub fn add_partition(
+        &mut self,
+        name: &str,
+        size: u64,
+        part_type: partition_types::Type,
+        flags: u64,
+    ) -> io::Result<u32> {
+        add_partition(
+            &mut self.partitions,
+            self.first_usable,
+            self.last_usable,
+            self.lb_size,
+            name,
+            size,
+            part_type,
+            flags,
+        )
+    }
+
+    /// Remove a partition from this table, see `GptDisk::remove_partition`.
+    pub fn remove_partition(
+        &mut self,
+        id: Option<u32>,
+        partguid: Option<uuid::Uuid>,
+    ) -> io::Result<u32> {
+        remove_partition(&mut self.partitions, id, partguid)
+    }
+
+    /// Find free space in the usable area.
+    /// Returns a list of (starting_lba, length in lba's).
+    pub fn find_free_sectors(&self) -> Vec<(u64, u64)> {
+        find_free_sectors(&self.partitions, self.first_usable, self.last_usable)
+    }
+
+    /// Find next highest partition id.
+    pub fn find_next_partition_id(&self) -> u32 {
+        find_next_partition_id(&self.partitions)
+    }
+
+    /// Retrieve partition entries.
+    pub fn partitions(&self) -> &BTreeMap<u32, Partition> {
+        &self.partitions
+    }
+
+    /// Replace partition entries.
+    pub fn update_partitions(&mut self, pp: BTreeMap<u32, Partition>) -> &Self {
+        self.partitions = pp;
+        self
+    }
+
+    /// Retrieve disk UUID.
+    pub fn guid(&self) -> &uuid::Uuid {
+        &self.disk_guid
+    }
+
+    /// Update disk UUID.
+    ///
+    /// If no UUID is specified, a new random one is generated.
+    pub fn update_guid(&mut self, uuid: Option<uuid::Uuid>) -> &Self {
+        self.disk_guid = uuid.unwrap_or_else(uuid::Uuid::new_v4);
+        self
+    }
+
+    /// Retrieve the logical block size.
+    pub fn logical_block_size(&self) -> &disk::LogicalBlockSize {
+        &self.lb_size
+    }
+
+    /// Retrieve the number of entries in the partition array.
+    pub fn num_parts(&self) -> u32 {
+        self.num_parts
+    }
+
+    /// Retrieve the size (in bytes) of a partition entry.
+    pub fn part_size(&self) -> u32 {
+        self.part_size
+    }
+
+    /// Retrieve the first usable LBA for partitions.
+    pub fn first_usable(&self) -> u64 {
+        self.first_usable
+    }
+
+    /// Retrieve the last usable LBA for partitions (inclusive).
+    pub fn last_usable(&self) -> u64 {
+        self.last_usable
+    }
+
+    /// Retrieve the LBA of the backup header.
+    pub fn backup_lba(&self) -> u64 {
+        self.backup_lba
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn add_partition(
+    partitions: &mut BTreeMap<u32, Partition>,
+    first_usable: u64,
+    last_usable: u64,
+    lb_size: disk::LogicalBlockSize,
+    name: &str,
+    size: u64,
+    part_type: partition_types::Type,
+    flags: u64,
+) -> io::Result<u32> {
+    let size_lba = match size.checked_div(lb_size.into()) {
+        Some(s) if s > 0 => s,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "size must be greater than {} which is the logical block size.",
+                    lb_size
+                ),
+            ));
+        }
+    };
+    // Find the lowest lba that is larger than size.
+    let free_sections = find_free_sectors(partitions, first_usable, last_usable);
+    for (starting_lba, length) in free_sections {
+        debug!("starting_lba {}, length {}", starting_lba, length);
+        if length >= size_lba {
+            // Found our free slice.
+            let partition_id = find_next_partition_id(partitions);
+            debug!(
+                "Adding partition id: {} {:?}.  first_lba: {} last_lba: {}",
+                partition_id,
+                part_type,
+                starting_lba,
+                starting_lba + size_lba - 1_u64
+            );
+            let part = Partition {
+                part_type_guid: part_type,
+                part_guid: uuid::Uuid::new_v4(),
+                first_lba: starting_lba,
+                last_lba: starting_lba + size_lba - 1_u64,
+                flags,
+                name: name.to_string(),
+            };
+            if let Some(p) = partitions.insert(partition_id, part.clone()) {
+                debug!("Replacing\n{}\nwith\n{}", p, part);
+            }
+            return Ok(partition_id);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unable to find enough space on drive",
+    ))
+}
+
+pub(crate) fn remove_partition(
+    partitions: &mut BTreeMap<u32, Partition>,
+    id: Option<u32>,
+    partguid: Option<uuid::Uuid>,
+) -> io::Result<u32> {
+    if let Some(part_id) = id {
+        if let Some(partition_id) = partitions.remove(&part_id) {
+            debug!("Removing partition number {}", partition_id);
+        }
+        return Ok(part_id);
+    }
+    if let Some(part_guid) = partguid {
+        let found = partitions
+            .iter()
+            .find(|(_, partition)| partition.part_guid == part_guid)
+            .map(|(key, _)| *key);
+        if let Some(key) = found {
+            if let Some(partition_id) = partitions.remove(&key) {
+                debug!("Removing partition number {}", partition_id);
+            }
+            return Ok(key);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unable to find partition to remove",
+    ))
+}
+
+pub(crate) fn find_free_sectors(
+    partitions: &BTreeMap<u32, Partition>,
+    first_usable: u64,
+    last_usable: u64,
+) -> Vec<(u64, u64)> {
+    trace!("first_usable: {}", first_usable);
+    let mut spans: Vec<(u64, u64)> = partitions
+        .values()
+        .filter(|p| p.is_used())
+        .map(|p| (p.first_lba, p.last_lba))
+        .collect();
+    spans.sort_unstable();
+
+    // Walk through used spans, collecting the gaps before each of them.
+    let mut free = Vec::new();
+    let mut next = first_usable;
+    for (first_lba, last_lba) in spans {
+        trace!("partition: ({}, {})", first_lba, last_lba);
+        let gap_end = first_lba.min(last_usable.saturating_add(1));
+        if gap_end > next {
+            free.push((next, gap_end - next));
+        }
+        next = next.max(last_lba.saturating_add(1));
+    }
+    trace!("last_usable: {}", last_usable);
+    if next <= last_usable {
+        free.push((next, last_usable - next + 1));
+    }
+    free
+}
+
+pub(crate) fn find_next_partition_id(partitions: &BTreeMap<u32, Partition>) -> u32 {
+    let max = match partitions
+        .iter()
+        // Skip unused partitions.
+        .filter(|p| p.1.is_used())
+        // Find the maximum id.
+        .max_by_key(|x| x.0)
+    {
+        Some(i) => *i.0,
+        // Partitions start at 1.
+        None => return 1,
+    };
+    for i in 1..max {
+        if partitions.get(&i).is_none() {
+            return i;
+        }
+    }
+    max + 1
+}
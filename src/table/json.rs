@@ -0,0 +1,451 @@
+//! Import and export of JSON layouts.
+//!
+//! The format follows `sfdisk --json`: a `partitiontable` object with the
+//! disk label fields and a `partitions` array. On export, it is extended
+//! with the full header parameters, both computed headers and a few
+//! derived per-partition fields; these are ignored on import, so
+//! `sfdisk --json` output can be loaded as well. Exports of a whole disk
+//! (see `GptDisk::to_json`) also describe its size and protective MBR.
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io;
+use std::str::FromStr;
+
+use super::sfdisk::{self, format_attributes, format_guid};
+use super::GptTable;
+use crate::disk;
+use crate::header::Header;
+use crate::mbr::ProtectiveMBR;
+
+/// Partition fields only present in our own exports.
+const DERIVED_PARTITION_FIELDS: [&str; 5] = ["node", "id", "lastlba", "bytes", "typename"];
+
+impl GptTable {
+    /// Serialize this table as JSON, compatible with `sfdisk --json`.
+    ///
+    /// Besides the sfdisk fields, the output carries the `tablelength`
+    /// and `entrysize` of the partition array, both `headers` as they
+    /// would be written, and for each partition its `id`, `lastlba`, size
+    /// in `bytes` and, for known types, the `typename` (see
+    /// `partition_types::Type::name`).
+    pub fn to_json(&self) -> io::Result<String> {
+        self.json_with(&[])
+    }
+
+    /// Serialize this table as JSON, like `to_json()`, along with the
+    /// size and protective MBR (if any) of the disk it was read from.
+    pub(crate) fn to_disk_json(&self, pmbr: Option<&ProtectiveMBR>, disk_size: u64) -> io::Result<String> {
+        let pmbr = match pmbr {
+            Some(pmbr) => pmbr_json(pmbr),
+            None => "null".to_string(),
+        };
+        self.json_with(&[format!("\"disksize\": {}", disk_size), format!("\"pmbr\": {}", pmbr)])
+    }
+
+    /// Serialize this table as JSON, with extra `partitiontable` fields.
+    fn json_with(&self, extra_fields: &[String]) -> io::Result<String> {
+        let lb_bytes: u64 = self.lb_size.into();
+        let primary = self.primary_header()?;
+        let backup = self.backup_header()?;
+
+        let mut out = String::new();
+        out.push_str("{\n   \"partitiontable\": {\n");
+        out.push_str("      \"label\": \"gpt\",\n");
+        out.push_str(&format!("      \"id\": {},\n", quote(&format_guid(&self.disk_guid))));
+        out.push_str("      \"unit\": \"sectors\",\n");
+        out.push_str(&format!("      \"firstlba\": {},\n", self.first_usable));
+        out.push_str(&format!("      \"lastlba\": {},\n", self.last_usable));
+        out.push_str(&format!("      \"sectorsize\": {},\n", lb_bytes));
+        out.push_str(&format!("      \"tablelength\": {},\n", self.num_parts));
+        out.push_str(&format!("      \"entrysize\": {},\n", self.part_size));
+        for field in extra_fields {
+            out.push_str(&format!("      {},\n", field));
+        }
+        out.push_str("      \"headers\": {\n");
+        out.push_str(&format!("         \"primary\": {},\n", header_json(&primary)));
+        out.push_str(&format!("         \"backup\": {}\n", header_json(&backup)));
+        out.push_str("      },\n");
+        out.push_str("      \"partitions\": [");
+        let used: Vec<_> = self.partitions.iter().filter(|(_, p)| p.is_used()).collect();
+        for (n, (id, part)) in used.iter().enumerate() {
+            let size = part.last_lba - part.first_lba + 1;
+            out.push_str(if n == 0 { "\n" } else { ",\n" });
+            out.push_str(&format!(
+                "         {{\"id\": {}, \"start\": {}, \"size\": {}, \"lastlba\": {}, \"bytes\": {}, \
+                 \"type\": {}, \"uuid\": {}",
+                id,
+                part.first_lba,
+                size,
+                part.last_lba,
+                size.saturating_mul(lb_bytes),
+                quote(part.part_type_guid.guid),
+                quote(&format_guid(&part.part_guid)),
+            ));
+            if let Some(name) = part.part_type_guid.name() {
+                out.push_str(&format!(", \"typename\": {}", quote(name)));
+            }
+            if !part.name.is_empty() {
+                out.push_str(&format!(", \"name\": {}", quote(&part.name)));
+            }
+            if part.flags != 0 {
+                out.push_str(&format!(", \"attrs\": {}", quote(&format_attributes(part.flags))));
+            }
+            out.push('}');
+        }
+        out.push_str(if used.is_empty() { "]\n" } else { "\n      ]\n" });
+        out.push_str("   }\n}\n");
+        Ok(out)
+    }
+
+    /// Parse a JSON layout, as produced by `to_json()` or `sfdisk --json`.
+    ///
+    /// Like `from_sfdisk_script()`, this requires the `lastlba` field, and
+    /// places the backup GPT right after it. Partitions are numbered by
+    /// their `id` or `node` field if present, sequentially otherwise.
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        let root = Parser::new(json).parse_document().map_err(json_error)?;
+        let pt = root
+            .get("partitiontable")
+            .ok_or_else(|| json_error("missing partitiontable object".to_string()))?;
+        if let Some(label) = pt.get("label") {
+            if label.as_str() != Some("gpt") {
+                return Err(json_error(format!("unsupported label type {:?}", label)));
+            }
+        }
+        if let Some(unit) = pt.get("unit") {
+            if unit.as_str() != Some("sectors") {
+                return Err(json_error(format!("unsupported unit {:?}", unit)));
+            }
+        }
+
+        let lb_size = match pt.get_u64("sectorsize")? {
+            Some(size) => disk::LogicalBlockSize::try_from(size)?,
+            None => disk::DEFAULT_SECTOR_SIZE,
+        };
+        let num_parts = match pt.get_u64("tablelength")? {
+            Some(len) => u32::try_from(len).map_err(|_| json_error("invalid tablelength".to_string()))?,
+            None => 128,
+        };
+        let disk_guid = match pt.get("id") {
+            Some(id) => Some(
+                id.as_str()
+                    .and_then(|s| uuid::Uuid::from_str(s).ok())
+                    .ok_or_else(|| json_error(format!("invalid disk id {:?}", id)))?,
+            ),
+            None => None,
+        };
+        let last_lba = pt
+            .get_u64("lastlba")?
+            .ok_or_else(|| json_error("missing lastlba".to_string()))?;
+        let first_lba = pt.get_u64("firstlba")?;
+        let mut table = sfdisk::table_from_usable(lb_size, disk_guid, num_parts, first_lba, last_lba)?;
+        if let Some(entry_size) = pt.get_u64("entrysize")? {
+            if entry_size != u64::from(table.part_size) {
+                return Err(json_error(format!("unsupported entrysize {}", entry_size)));
+            }
+        }
+
+        let parts = match pt.get("partitions") {
+            Some(Value::Array(parts)) => parts.as_slice(),
+            Some(other) => return Err(json_error(format!("invalid partitions {:?}", other))),
+            None => &[],
+        };
+        let mut next_id = 1;
+        let mut next_start = table.first_usable;
+        for entry in parts {
+            let members = match entry {
+                Value::Object(members) => members,
+                other => return Err(json_error(format!("invalid partition {:?}", other))),
+            };
+            let id = match (entry.get_u64("id")?, entry.get("node").and_then(Value::as_str)) {
+                (Some(id), _) => u32::try_from(id).ok().filter(|id| *id > 0),
+                (None, Some(node)) => sfdisk::partition_id_from_node(node),
+                (None, None) => Some(next_id),
+            }
+            .ok_or_else(|| json_error("invalid partition id".to_string()))?;
+
+            let mut fields = Vec::new();
+            for (key, value) in members {
+                if DERIVED_PARTITION_FIELDS.contains(&key.as_str()) {
+                    continue;
+                }
+                let value = match value {
+                    Value::String(s) | Value::Number(s) => s.clone(),
+                    Value::Bool(true) => "true".to_string(),
+                    other => return Err(json_error(format!("invalid partition field {:?}", other))),
+                };
+                fields.push((key.clone(), value));
+            }
+            let part = sfdisk::partition_from_fields(&fields, next_start, table.last_usable)
+                .map_err(|e| json_error(format!("partition {}: {}", id, e)))?;
+            next_id = id + 1;
+            next_start = part.last_lba + 1;
+            if table.partitions.insert(id, part).is_some() {
+                return Err(json_error(format!("duplicate partition {}", id)));
+            }
+        }
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+fn header_json(h: &Header) -> String {
+    format!(
+        "{{\"lba\": {}, \"backuplba\": {}, \"partitionslba\": {}, \"crc32\": {}, \
+         \"partitionscrc32\": {}}}",
+        h.current_lba, h.backup_lba, h.part_start, h.crc32, h.crc32_parts
+    )
+}
+
+fn pmbr_json(pmbr: &ProtectiveMBR) -> String {
+    let records: Vec<String> = (0..4)
+        .filter_map(|i| pmbr.partition(i))
+        .map(|r| {
+            format!(
+                "{{\"bootindicator\": {}, \"type\": \"{:02x}\", \"start\": {}, \"size\": {}}}",
+                r.boot_indicator, r.os_type, r.lb_start, r.lb_size
+            )
+        })
+        .collect();
+    format!(
+        "{{\n         \"signature\": \"0x{:08x}\",\n         \"records\": [\n            {}\n         ]\n      }}",
+        u32::from_le_bytes(*pmbr.disk_signature()),
+        records.join(",\n            ")
+    )
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("invalid JSON layout: {}", msg))
+}
+
+/// Decode a `\u` escape, after its `u`. Characters outside the BMP are
+/// escaped as a surrogate pair, and unpaired surrogates are rejected.
+fn unicode_escape(chars: &mut std::str::CharIndices) -> Option<char> {
+    let code = match hex4(chars)? {
+        high @ 0xD800..=0xDBFF => {
+            if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                return None;
+            }
+            match hex4(chars)? {
+                low @ 0xDC00..=0xDFFF => 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00),
+                _ => return None,
+            }
+        }
+        code => code,
+    };
+    std::char::from_u32(code)
+}
+
+/// Read the 4 hexadecimal digits of a `\u` escape.
+fn hex4(chars: &mut std::str::CharIndices) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let (_, c) = chars.next()?;
+        code = code << 4 | c.to_digit(16)?;
+    }
+    Some(code)
+}
+
+/// A parsed JSON value. Numbers are kept as text and converted on use.
+#[derive(Debug)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get_u64(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Number(n)) => n
+                .parse()
+                .map(Some)
+                .map_err(|_| json_error(format!("invalid {} {}", key, n))),
+            Some(other) => Err(json_error(format!("invalid {} {:?}", key, other))),
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// A minimal recursive-descent JSON parser.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    fn parse_document(&mut self) -> Result<Value, String> {
+        let value = self.parse_value(0)?;
+        self.skip_whitespace();
+        if self.pos != self.input.len() {
+            return Err(format!("trailing characters at offset {}", self.pos));
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value, String> {
+        // Layouts are shallow; bound recursion on hostile input.
+        if depth > 32 {
+            return Err("nesting too deep".to_string());
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.eat('}') {
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.expect(':')?;
+                    members.push((key, self.parse_value(depth + 1)?));
+                    if !self.eat(',') {
+                        self.expect('}')?;
+                        return Ok(Value::Object(members));
+                    }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.eat(']') {
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value(depth + 1)?);
+                    if !self.eat(',') {
+                        self.expect(']')?;
+                        return Ok(Value::Array(items));
+                    }
+                }
+            }
+            Some('"') => self.parse_string().map(Value::String),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let rest = &self.input[self.pos..];
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                Ok(Value::Number(rest[..len].to_string()))
+            }
+            _ => {
+                for (word, value) in &[("true", Some(true)), ("false", Some(false)), ("null", None)] {
+                    if self.input[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value.map_or(Value::Null, Value::Bool));
+                    }
+                }
+                Err(format!("unexpected character at offset {}", self.pos))
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        if !self.eat('"') {
+            return Err(format!("expected string at offset {}", self.pos));
+        }
+        let mut s = String::new();
+        let mut chars = self.input[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escaped = chars.next().map(|(_, c)| c);
+                    match escaped {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('/') => s.push('/'),
+                        Some('b') => s.push('\u{8}'),
+                        Some('f') => s.push('\u{c}'),
+                        Some('n') => s.push('\n'),
+                        Some('r') => s.push('\r'),
+                        Some('t') => s.push('\t'),
+                        Some('u') => {
+                            let c = unicode_escape(&mut chars).ok_or_else(|| {
+                                format!("invalid or unpaired \\u escape at offset {}", self.pos + i)
+                            })?;
+                            s.push(c);
+                        }
+                        _ => return Err(format!("invalid escape at offset {}", self.pos + i)),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at offset {}", c, self.pos))
+        }
+    }
+}
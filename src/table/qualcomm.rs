@@ -0,0 +1,548 @@
+//! Qualcomm `partition.xml` layouts and flashing programs.
+//!
+//! Qualcomm BSPs describe the partitions of each storage LUN in a
+//! `partition.xml` file, with one `<physical_partition>` element per LUN.
+//! For each LUN, the vendor tooling generates the GPT images
+//! (`gpt_main<N>.bin`, `gpt_backup<N>.bin`) and two XML programs for the
+//! flashing tool: `rawprogram<N>.xml`, listing what to write where, and
+//! `patch<N>.xml`, fixing up the GPT fields which depend on the actual
+//! disk size (expressed in terms of `NUM_DISK_SECTORS`) at flashing time.
+//!
+//! Partitions are laid out back to back right after the primary partition
+//! array; `WRITE_PROTECT_BOUNDARY_IN_KB` alignment is not supported.
+
+use log::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::io;
+use std::str::FromStr;
+
+use super::GptTable;
+use crate::disk;
+use crate::partition::Partition;
+use crate::partition_types::Type;
+
+/// Number of entries in the generated partition arrays.
+const NUM_PARTS: u32 = 128;
+
+/// Read-only partition attribute, as set by Qualcomm tooling.
+const ATTR_READONLY: u64 = 1 << 60;
+/// Hidden partition attribute.
+const ATTR_HIDDEN: u64 = 1 << 62;
+/// Do-not-automount partition attribute.
+const ATTR_DONTAUTOMOUNT: u64 = 1 << 63;
+/// Required (system) partition attribute.
+const ATTR_SYSTEM: u64 = 1;
+/// Legacy-BIOS bootable partition attribute.
+const ATTR_BOOTABLE: u64 = 1 << 2;
+
+/// A `partition.xml` layout, for all LUNs of a device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartitionXml {
+    /// Sector size from the parser instructions (`SECTOR_SIZE_IN_BYTES`).
+    pub sector_size: u64,
+    /// Whether the last partition of a LUN fills the rest of the disk when
+    /// its size is 0 (`GROW_LAST_PARTITION_TO_FILL_DISK`).
+    pub grow_last: bool,
+    /// Partitions of each physical partition (LUN), in on-disk order.
+    pub luns: Vec<Vec<QcomPartition>>,
+}
+
+/// A partition entry in `partition.xml`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QcomPartition {
+    /// Partition label.
+    pub label: String,
+    /// Partition size in bytes, as given by `size_in_kb`.
+    pub size: u64,
+    /// Partition type.
+    pub part_type: Type,
+    /// Partition GUID (`uniqueguid`), random if None.
+    pub uuid: Option<uuid::Uuid>,
+    /// GPT attribute flags, from `bootable`, `readonly`, `hidden`,
+    /// `dontautomount` and `system`.
+    pub flags: u64,
+    /// Image to flash to this partition, if any.
+    pub filename: String,
+    /// Whether the image is in Android sparse format.
+    pub sparse: bool,
+}
+
+/// The GPT and flashing programs generated for one LUN.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LunImages {
+    /// Partition table, for a disk just large enough to hold it.
+    pub table: GptTable,
+    /// Content of `gpt_main<N>.bin`: protective MBR, header and array.
+    pub gpt_main: Vec<u8>,
+    /// Content of `gpt_backup<N>.bin`: backup array and header.
+    pub gpt_backup: Vec<u8>,
+    /// Content of `rawprogram<N>.xml`.
+    pub rawprogram: String,
+    /// Content of `patch<N>.xml`.
+    pub patch: String,
+}
+
+impl PartitionXml {
+    /// Parse a `partition.xml` file.
+    pub fn parse(xml: &str) -> io::Result<Self> {
+        let mut layout = PartitionXml { sector_size: 512, grow_last: false, luns: Vec::new() };
+        let mut in_instructions = false;
+        for token in tokenize(xml)? {
+            match token {
+                Token::Open(name, attrs, empty) => match name.as_str() {
+                    "parser_instructions" => in_instructions = !empty,
+                    "physical_partition" => layout.luns.push(Vec::new()),
+                    "partition" => {
+                        let lun = layout
+                            .luns
+                            .last_mut()
+                            .ok_or_else(|| xml_error("partition outside of physical_partition"))?;
+                        lun.push(QcomPartition::from_attributes(&attrs)?);
+                    }
+                    _ => {}
+                },
+                Token::Close(name) => {
+                    if name == "parser_instructions" {
+                        in_instructions = false;
+                    }
+                }
+                Token::Text(ref text) if in_instructions => {
+                    for line in text.lines() {
+                        let mut kv = line.splitn(2, '=');
+                        let key = kv.next().unwrap_or_default().trim();
+                        let value = kv.next().unwrap_or_default().trim();
+                        match key {
+                            "SECTOR_SIZE_IN_BYTES" => {
+                                layout.sector_size = value
+                                    .parse()
+                                    .map_err(|_| xml_error("invalid SECTOR_SIZE_IN_BYTES"))?
+                            }
+                            "GROW_LAST_PARTITION_TO_FILL_DISK" => {
+                                layout.grow_last = value.eq_ignore_ascii_case("true")
+                            }
+                            "WRITE_PROTECT_BOUNDARY_IN_KB" if value != "0" => {
+                                warn!("ignoring unsupported WRITE_PROTECT_BOUNDARY_IN_KB={}", value)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Token::Text(_) => {}
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Describe existing partition tables as a layout, one LUN per table.
+    ///
+    /// If the last partition of a table extends to its last usable LBA,
+    /// it is marked as growing to fill the disk, with a size of 0; the
+    /// last partitions of other tables keep their size.
+    pub fn from_tables(tables: &[GptTable]) -> Self {
+        let mut layout = PartitionXml {
+            sector_size: tables.first().map_or(512, |t| (*t.logical_block_size()).into()),
+            grow_last: false,
+            luns: Vec::with_capacity(tables.len()),
+        };
+        for table in tables {
+            let lb_bytes: u64 = (*table.logical_block_size()).into();
+            let mut parts: Vec<&Partition> = table.partitions().values().filter(|p| p.is_used()).collect();
+            parts.sort_by_key(|p| p.first_lba);
+            let grows = parts.last().map_or(false, |p| p.last_lba == table.last_usable());
+            layout.grow_last |= grows;
+            let lun = parts
+                .iter()
+                .enumerate()
+                .map(|(i, p)| QcomPartition {
+                    label: p.name.clone(),
+                    size: if grows && i + 1 == parts.len() {
+                        0
+                    } else {
+                        (p.last_lba - p.first_lba + 1) * lb_bytes
+                    },
+                    part_type: p.part_type_guid.clone(),
+                    uuid: Some(p.part_guid),
+                    flags: p.flags,
+                    filename: String::new(),
+                    sparse: false,
+                })
+                .collect();
+            layout.luns.push(lun);
+        }
+        layout
+    }
+
+    /// Serialize this layout as a `partition.xml` file.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\"?>\n<configuration>\n");
+        xml.push_str("  <parser_instructions>\n");
+        xml.push_str("    WRITE_PROTECT_BOUNDARY_IN_KB = 0\n");
+        let _ = writeln!(xml, "    SECTOR_SIZE_IN_BYTES = {}", self.sector_size);
+        let _ = writeln!(xml, "    GROW_LAST_PARTITION_TO_FILL_DISK = {}", self.grow_last);
+        xml.push_str("  </parser_instructions>\n");
+        for lun in &self.luns {
+            xml.push_str("  <physical_partition>\n");
+            for p in lun {
+                let _ = write!(
+                    xml,
+                    "    <partition label=\"{}\" size_in_kb=\"{}\" type=\"{}\"",
+                    escape(&p.label),
+                    format_kb(p.size),
+                    p.part_type.guid
+                );
+                if let Some(uuid) = p.uuid {
+                    let _ = write!(xml, " uniqueguid=\"{}\"", uuid.to_hyphenated().to_string().to_uppercase());
+                }
+                for (name, bit) in &[
+                    ("bootable", ATTR_BOOTABLE),
+                    ("readonly", ATTR_READONLY),
+                    ("hidden", ATTR_HIDDEN),
+                    ("dontautomount", ATTR_DONTAUTOMOUNT),
+                    ("system", ATTR_SYSTEM),
+                ] {
+                    let _ = write!(xml, " {}=\"{}\"", name, p.flags & bit != 0);
+                }
+                let _ = writeln!(xml, " filename=\"{}\" sparse=\"{}\"/>", escape(&p.filename), p.sparse);
+            }
+            xml.push_str("  </physical_partition>\n");
+        }
+        xml.push_str("</configuration>\n");
+        xml
+    }
+
+    /// Generate the GPT images, `rawprogram<lun>.xml` and `patch<lun>.xml`
+    /// for LUN `lun`, with `sector_size` sectors.
+    ///
+    /// The table is built for the smallest disk that holds all partitions
+    /// (with a single sector for a growing last partition); `patch<lun>.xml`
+    /// moves the backup GPT and, if the last partition grows, extends it to
+    /// the actual disk size when flashing.
+    pub fn generate(&self, lun: usize) -> io::Result<LunImages> {
+        let parts = self
+            .luns
+            .get(lun)
+            .ok_or_else(|| xml_error(&format!("no physical_partition for LUN {}", lun)))?;
+        let lb_size = disk::LogicalBlockSize::try_from(self.sector_size)?;
+        let lb_bytes: u64 = lb_size.into();
+        let overflow = || xml_error(&format!("partitions of LUN {} overflow the disk", lun));
+        let grow_last = self.grow_last && parts.last().map_or(false, |p| p.size == 0);
+        let array_lbs = (u64::from(NUM_PARTS) * 128 + lb_bytes - 1) / lb_bytes;
+        let first_usable = 2 + array_lbs;
+
+        let mut partitions = BTreeMap::new();
+        let mut sectors = Vec::with_capacity(parts.len());
+        let mut cursor = first_usable;
+        for (i, p) in parts.iter().enumerate() {
+            let grows = grow_last && i + 1 == parts.len();
+            let len = if grows {
+                1
+            } else {
+                p.size.checked_add(lb_bytes - 1).ok_or_else(overflow)? / lb_bytes
+            };
+            if len == 0 {
+                return Err(xml_error(&format!("partition {} has no size", p.label)));
+            }
+            let next = cursor.checked_add(len).ok_or_else(overflow)?;
+            partitions.insert(
+                u32::try_from(i + 1).map_err(|_| xml_error("too many partitions"))?,
+                Partition {
+                    part_type_guid: p.part_type.clone(),
+                    part_guid: p.uuid.unwrap_or_else(uuid::Uuid::new_v4),
+                    first_lba: cursor,
+                    last_lba: next - 1,
+                    flags: p.flags,
+                    name: p.label.clone(),
+                },
+            );
+            sectors.push((cursor, if grows { 0 } else { len }));
+            cursor = next;
+        }
+        let disk_size = cursor
+            .checked_add(array_lbs + 1)
+            .and_then(|lbs| lbs.checked_mul(lb_bytes))
+            .ok_or_else(overflow)?;
+        let mut table = GptTable::new(lb_size, disk_size, NUM_PARTS, None)?;
+        table.update_partitions(partitions);
+        table.validate()?;
+        let (gpt_main, gpt_backup) = table.to_flash_images()?;
+
+        // rawprogram: partition images, then both GPT copies.
+        let mut rawprogram = String::from("<?xml version=\"1.0\" ?>\n<data>\n");
+        rawprogram.push_str("  <!--NOTE: This is an ** Autogenerated file **-->\n");
+        let program = |label: &str, filename: &str, sparse: bool, start: &str, start_byte: &str, num: u64| {
+            format!(
+                "  <program SECTOR_SIZE_IN_BYTES=\"{}\" file_sector_offset=\"0\" filename=\"{}\" \
+                 label=\"{}\" num_partition_sectors=\"{}\" partofsingleimage=\"false\" \
+                 physical_partition_number=\"{}\" readbackverify=\"false\" size_in_KB=\"{}.{}\" \
+                 sparse=\"{}\" start_byte_hex=\"{}\" start_sector=\"{}\"/>\n",
+                lb_bytes,
+                escape(filename),
+                escape(label),
+                num,
+                lun,
+                num * lb_bytes / 1024,
+                num * lb_bytes % 1024 * 10 / 1024,
+                sparse,
+                start_byte,
+                start,
+            )
+        };
+        for (p, (start, num)) in parts.iter().zip(&sectors) {
+            let start_byte = format!("{:#x}", start * lb_bytes);
+            rawprogram.push_str(&program(&p.label, &p.filename, p.sparse, &start.to_string(), &start_byte, *num));
+        }
+        let main_lbs = gpt_main.len() as u64 / lb_bytes;
+        let backup_lbs = gpt_backup.len() as u64 / lb_bytes;
+        rawprogram.push_str(&program(
+            "PrimaryGPT",
+            &format!("gpt_main{}.bin", lun),
+            false,
+            "0",
+            "0x0",
+            main_lbs,
+        ));
+        rawprogram.push_str(&program(
+            "BackupGPT",
+            &format!("gpt_backup{}.bin", lun),
+            false,
+            &Sector::FromEnd(backup_lbs).to_string(),
+            &format!("({}*NUM_DISK_SECTORS)-{}.", lb_bytes, backup_lbs * lb_bytes),
+            backup_lbs,
+        ));
+        rawprogram.push_str("</data>\n");
+
+        let patch = patches(lun, lb_bytes, array_lbs, grow_last, parts.len())?;
+        Ok(LunImages { table, gpt_main, gpt_backup, rawprogram, patch })
+    }
+}
+
+impl QcomPartition {
+    fn from_attributes(attrs: &BTreeMap<String, String>) -> io::Result<Self> {
+        let get = |name: &str| attrs.get(name).map(String::as_str);
+        let flag = |name: &str| get(name).map_or(false, |v| v.eq_ignore_ascii_case("true"));
+        let label = get("label").ok_or_else(|| xml_error("partition without label"))?;
+        let size = parse_kb(get("size_in_kb").unwrap_or("0"))
+            .ok_or_else(|| xml_error(&format!("invalid size_in_kb for partition {}", label)))?;
+        let type_guid = get("type").ok_or_else(|| xml_error(&format!("partition {} has no type", label)))?;
+        let part_type = uuid::Uuid::from_str(type_guid.trim_matches(|c| c == '{' || c == '}'))
+            .ok()
+            .and_then(|u| Type::from_uuid(&u).ok())
+            .ok_or_else(|| xml_error(&format!("unsupported type {} for partition {}", type_guid, label)))?;
+        let uuid = match get("uniqueguid") {
+            Some(v) => Some(
+                uuid::Uuid::from_str(v.trim_matches(|c| c == '{' || c == '}'))
+                    .map_err(|_| xml_error(&format!("invalid uniqueguid for partition {}", label)))?,
+            ),
+            None => None,
+        };
+        let mut flags = 0;
+        for (name, bit) in &[
+            ("bootable", ATTR_BOOTABLE),
+            ("readonly", ATTR_READONLY),
+            ("hidden", ATTR_HIDDEN),
+            ("dontautomount", ATTR_DONTAUTOMOUNT),
+            ("system", ATTR_SYSTEM),
+        ] {
+            if flag(name) {
+                flags |= bit;
+            }
+        }
+        Ok(QcomPartition {
+            label: label.to_string(),
+            size,
+            part_type,
+            uuid,
+            flags,
+            filename: get("filename").unwrap_or_default().to_string(),
+            sparse: flag("sparse"),
+        })
+    }
+}
+
+/// A sector in a patch target, from its start or, for the whole disk,
+/// from its end (in terms of `NUM_DISK_SECTORS`).
+#[derive(Clone, Copy, Debug)]
+enum Sector {
+    Abs(u64),
+    FromEnd(u64),
+}
+
+impl Sector {
+    /// Return the sector `lbs` sectors after this one.
+    fn offset(self, lbs: u64) -> io::Result<Self> {
+        match self {
+            Sector::Abs(n) => n.checked_add(lbs).map(Sector::Abs),
+            Sector::FromEnd(n) => n.checked_sub(lbs).map(Sector::FromEnd),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "patch sector out of range"))
+    }
+}
+
+impl fmt::Display for Sector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sector::Abs(n) => write!(f, "{}", n),
+            Sector::FromEnd(n) => write!(f, "NUM_DISK_SECTORS-{}.", n),
+        }
+    }
+}
+
+/// Build `patch<lun>.xml`, for both the GPT image files and the disk.
+fn patches(lun: usize, lb_bytes: u64, array_lbs: u64, grow_last: bool, num_parts: usize) -> io::Result<String> {
+    let array_bytes = u64::from(NUM_PARTS) * 128;
+    let last_usable = Sector::FromEnd(array_lbs + 2).to_string();
+    let backup_lba = Sector::FromEnd(1).to_string();
+    let backup_array = Sector::FromEnd(array_lbs + 1);
+    let mut xml = String::from("<?xml version=\"1.0\" ?>\n<patches>\n");
+    xml.push_str("  <!--NOTE: This is an ** Autogenerated file **-->\n");
+
+    for (target, main_base, backup_base) in &[
+        // Sector offsets of the primary and backup copies in each target.
+        (format!("gpt_main{}.bin", lun), Some(Sector::Abs(0)), None),
+        (format!("gpt_backup{}.bin", lun), None, Some(Sector::Abs(0))),
+        ("DISK".to_string(), Some(Sector::Abs(0)), Some(backup_array)),
+    ] {
+        let mut patch = |sector: Sector, offset: u64, size: u64, value: &str, what: &str| {
+            let _ = writeln!(
+                xml,
+                "  <patch SECTOR_SIZE_IN_BYTES=\"{}\" byte_offset=\"{}\" filename=\"{}\" \
+                 physical_partition_number=\"{}\" size_in_bytes=\"{}\" start_sector=\"{}\" \
+                 value=\"{}\" what=\"{}\"/>",
+                lb_bytes, offset, target, lun, size, sector, value, what
+            );
+        };
+        let entry = ((num_parts.max(1) - 1) as u64) * 128;
+
+        if let Some(base) = *main_base {
+            if grow_last && num_parts > 0 {
+                patch(base.offset(2 + entry / lb_bytes)?, entry % lb_bytes + 40, 8, &last_usable,
+                      "Update last partition with actual size in Primary Header.");
+            }
+            let hdr = base.offset(1)?;
+            patch(hdr, 32, 8, &backup_lba, "Update Primary Header with BackupGPT Header Location.");
+            patch(hdr, 48, 8, &last_usable, "Update Primary Header with LastUseableLBA.");
+            patch(hdr, 88, 4, &format!("CRC32({},{})", base.offset(2)?, array_bytes),
+                  "Update Primary Header with CRC of Partition Array.");
+            patch(hdr, 16, 4, "0", "Zero Out Header CRC in Primary Header.");
+            patch(hdr, 16, 4, &format!("CRC32({},92)", hdr), "Update Primary Header with CRC of Primary Header.");
+        }
+        if let Some(base) = *backup_base {
+            if grow_last && num_parts > 0 {
+                patch(base.offset(entry / lb_bytes)?, entry % lb_bytes + 40, 8, &last_usable,
+                      "Update last partition with actual size in Backup Header.");
+            }
+            let hdr = base.offset(array_lbs)?;
+            patch(hdr, 24, 8, &backup_lba, "Update Backup Header with CurrentLBA.");
+            patch(hdr, 48, 8, &last_usable, "Update Backup Header with LastUseableLBA.");
+            patch(hdr, 72, 8, &backup_array.to_string(), "Update Backup Header with Partition Array Location.");
+            patch(hdr, 88, 4, &format!("CRC32({},{})", base, array_bytes),
+                  "Update Backup Header with CRC of Partition Array.");
+            patch(hdr, 16, 4, "0", "Zero Out Header CRC in Backup Header.");
+            patch(hdr, 16, 4, &format!("CRC32({},92)", hdr), "Update Backup Header with CRC of Backup Header.");
+        }
+    }
+    xml.push_str("</patches>\n");
+    Ok(xml)
+}
+
+/// Format a size in bytes as an exact, possibly fractional, number of KiB.
+fn format_kb(bytes: u64) -> String {
+    let mut kb = (bytes / 1024).to_string();
+    let mut rem = bytes % 1024;
+    if rem != 0 {
+        kb.push('.');
+        while rem != 0 {
+            rem *= 10;
+            kb.push_str(&(rem / 1024).to_string());
+            rem %= 1024;
+        }
+    }
+    kb
+}
+
+/// Parse a `size_in_kb` value, possibly fractional, into bytes.
+fn parse_kb(value: &str) -> Option<u64> {
+    let mut parts = value.trim().splitn(2, '.');
+    let mut bytes = parts.next()?.parse::<u64>().ok()?.checked_mul(1024)?;
+    if let Some(frac) = parts.next().filter(|f| !f.is_empty()) {
+        let denom = 10u64.checked_pow(frac.len() as u32)?;
+        bytes = bytes.checked_add(frac.parse::<u64>().ok()?.checked_mul(1024)? / denom)?;
+    }
+    Some(bytes)
+}
+
+/// An XML token: a start tag (name, attributes, self-closing), an end
+/// tag, or text.
+#[derive(Debug)]
+enum Token {
+    Open(String, BTreeMap<String, String>, bool),
+    Close(String),
+    Text(String),
+}
+
+/// Split an XML document into tokens, skipping the prolog and comments.
+fn tokenize(xml: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = xml;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or_else(|| xml_error("unterminated comment"))?;
+            rest = &rest[end + 3..];
+        } else if rest.starts_with("<?") {
+            let end = rest.find("?>").ok_or_else(|| xml_error("unterminated declaration"))?;
+            rest = &rest[end + 2..];
+        } else if rest.starts_with('<') {
+            let end = rest.find('>').ok_or_else(|| xml_error("unterminated tag"))?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('/') {
+                tokens.push(Token::Close(tag.trim_start_matches('/').trim().to_string()));
+                continue;
+            }
+            let empty = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+            tokens.push(Token::Open(tag[..name_end].to_string(), parse_attributes(&tag[name_end..])?, empty));
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(Token::Text(unescape(&rest[..end])));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_attributes(mut s: &str) -> io::Result<BTreeMap<String, String>> {
+    let mut attrs = BTreeMap::new();
+    loop {
+        s = s.trim_start();
+        if s.is_empty() {
+            return Ok(attrs);
+        }
+        let eq = s.find('=').ok_or_else(|| xml_error("invalid attribute"))?;
+        let name = s[..eq].trim().to_string();
+        s = s[eq + 1..].trim_start();
+        let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'').ok_or_else(|| xml_error("unquoted attribute"))?;
+        let end = s[1..].find(quote).ok_or_else(|| xml_error("unterminated attribute"))?;
+        attrs.insert(name, unescape(&s[1..=end]));
+        s = &s[end + 2..];
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn xml_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("invalid partition.xml: {}", msg))
+}
@@ -0,0 +1,505 @@
+//! Import of systemd-repart partition definitions.
+//!
+//! `systemd-repart` describes the wanted partitions of a disk in
+//! `repart.d/*.conf` files, one partition per file, processed in file
+//! name order. This module parses such files, and
+//! `GptTable::apply_repart()` allocates them the way `systemd-repart` does:
+//!
+//!  * each definition first matches an existing partition of the same
+//!    type (the n-th definition of a type matches the n-th partition of
+//!    that type on disk), which may then only grow into the free space
+//!    directly after it;
+//!  * remaining definitions become new partitions, placed in the smallest
+//!    free area that fits their minimum size and padding; if they do not
+//!    all fit, those with the highest (positive) `Priority=` are dropped
+//!    until they do;
+//!  * free space in each area is then shared according to `Weight=`,
+//!    within `SizeMinBytes=` and `SizeMaxBytes=`.
+//!
+//! Sizes are aligned to 4096 bytes. Unlike `systemd-repart`, partition
+//! UUIDs without `UUID=` are random rather than derived from the machine ID,
+//! and `Type=root` is rejected: it names the root partition type of the
+//! host architecture, which need not be the one of the target.
+
+use log::*;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::{fs, io, path};
+
+use super::GptTable;
+use crate::partition::Partition;
+use crate::partition_types::{self, Type};
+
+/// Default value of `Weight=`.
+pub const DEFAULT_WEIGHT: u32 = 1000;
+
+/// Default value of `SizeMinBytes=` for new partitions.
+pub const DEFAULT_SIZE_MIN_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Alignment of partition starts and sizes (bytes).
+const GRAIN_SIZE: u64 = 4096;
+
+/// Symbolic names accepted by `Type=`, see `type_from_name`.
+const TYPE_NAMES: [&str; 10] = [
+    "esp",
+    "xbootldr",
+    "swap",
+    "home",
+    "srv",
+    "linux-generic",
+    "root-x86",
+    "root-x86-64",
+    "root-arm",
+    "root-arm64",
+];
+
+/// A partition definition, as read from a `repart.d/*.conf` file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Definition {
+    /// Name of the definition, usually its file name.
+    pub name: String,
+    /// Partition type (`Type=`).
+    pub part_type: Type,
+    /// Partition label (`Label=`), defaulting to the symbolic type name.
+    pub label: String,
+    /// Partition UUID for new partitions (`UUID=`), random if None.
+    pub uuid: Option<uuid::Uuid>,
+    /// Minimum partition size in bytes (`SizeMinBytes=`).
+    pub size_min_bytes: Option<u64>,
+    /// Maximum partition size in bytes (`SizeMaxBytes=`).
+    pub size_max_bytes: Option<u64>,
+    /// Share of free space to assign to this partition (`Weight=`).
+    pub weight: u32,
+    /// Drop priority when space runs out, higher first (`Priority=`).
+    pub priority: i32,
+    /// Minimum free space after the partition in bytes (`PaddingMinBytes=`).
+    pub padding_min_bytes: u64,
+    /// Partition attribute flags for new partitions (`Flags=`).
+    pub flags: u64,
+}
+
+impl Definition {
+    /// Parse the content of a `repart.d` file named `name`.
+    ///
+    /// Only the `[Partition]` section is considered, and `Type=` is
+    /// required. Unsupported settings are ignored with a warning.
+    pub fn parse(name: &str, content: &str) -> io::Result<Self> {
+        let mut section = String::new();
+        let mut settings: BTreeMap<String, String> = BTreeMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].to_string();
+                if section != "Partition" {
+                    warn!("{}: ignoring unknown section [{}]", name, section);
+                }
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap_or_default().trim();
+            let value = kv.next().ok_or_else(|| {
+                definition_error(name, &format!("line {}: expected 'Key=value'", n + 1))
+            })?;
+            if section == "Partition" {
+                // Later assignments override earlier ones.
+                settings.insert(key.to_string(), value.trim().to_string());
+            }
+        }
+
+        let type_value = settings
+            .get("Type")
+            .ok_or_else(|| definition_error(name, "missing Type="))?;
+        if type_value == "root" {
+            return Err(definition_error(
+                name,
+                "Type=root depends on the target architecture, use root-x86-64, root-arm64, etc.",
+            ));
+        }
+        let part_type = type_from_name(type_value)
+            .or_else(|| uuid::Uuid::from_str(type_value).ok().and_then(|u| Type::from_uuid(&u).ok()))
+            .ok_or_else(|| definition_error(name, &format!("unsupported Type={}", type_value)))?;
+        let mut def = Definition {
+            name: name.to_string(),
+            label: type_name(&part_type).map_or_else(|| part_type.guid.to_string(), String::from),
+            part_type,
+            uuid: None,
+            size_min_bytes: None,
+            size_max_bytes: None,
+            weight: DEFAULT_WEIGHT,
+            priority: 0,
+            padding_min_bytes: 0,
+            flags: 0,
+        };
+        for (key, value) in &settings {
+            let invalid = || definition_error(name, &format!("invalid {}={}", key, value));
+            match key.as_str() {
+                "Type" => {}
+                "Label" => def.label = value.clone(),
+                "UUID" => def.uuid = Some(uuid::Uuid::from_str(value).map_err(|_| invalid())?),
+                "SizeMinBytes" => def.size_min_bytes = Some(parse_size(value).ok_or_else(invalid)?),
+                "SizeMaxBytes" => def.size_max_bytes = Some(parse_size(value).ok_or_else(invalid)?),
+                "PaddingMinBytes" => def.padding_min_bytes = parse_size(value).ok_or_else(invalid)?,
+                "Weight" => {
+                    def.weight = value
+                        .parse()
+                        .ok()
+                        .filter(|w| *w <= 1000 * 1000)
+                        .ok_or_else(invalid)?
+                }
+                "Priority" => {
+                    def.priority = value
+                        .parse()
+                        .ok()
+                        .filter(|p: &i32| p.abs() <= 1000)
+                        .ok_or_else(invalid)?
+                }
+                "Flags" => {
+                    def.flags = if value.starts_with("0x") || value.starts_with("0X") {
+                        u64::from_str_radix(&value[2..], 16).ok()
+                    } else {
+                        value.parse().ok()
+                    }
+                    .ok_or_else(invalid)?
+                }
+                _ => warn!("{}: ignoring unsupported setting {}=", name, key),
+            }
+        }
+        if let (Some(min), Some(max)) = (def.size_min_bytes, def.size_max_bytes) {
+            if min > max {
+                return Err(definition_error(name, "SizeMinBytes= is larger than SizeMaxBytes="));
+            }
+        }
+        Ok(def)
+    }
+}
+
+/// Read all `*.conf` definitions in `dir`, sorted by file name.
+pub fn read_definitions(dir: &path::Path) -> io::Result<Vec<Definition>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_file() && path.extension().map_or(false, |ext| ext == "conf") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut defs = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        debug!("reading repart definition {}", name);
+        defs.push(Definition::parse(&name, &fs::read_to_string(&path)?)?);
+    }
+    Ok(defs)
+}
+
+impl GptTable {
+    /// Allocate systemd-repart definitions on this table, following
+    /// `systemd-repart` semantics (see the `repart` module documentation).
+    ///
+    /// Returns, for each definition, the id of the matched or newly created
+    /// partition, or None if it was dropped for lack of space. On error,
+    /// the table is left unchanged.
+    pub fn apply_repart(&mut self, defs: &[Definition]) -> io::Result<Vec<Option<u32>>> {
+        let lb_bytes: u64 = (*self.logical_block_size()).into();
+        let grain = (GRAIN_SIZE / lb_bytes).max(1);
+        let align_up = |lba: u64| (lba + grain - 1) / grain * grain;
+        let align_down = |lba: u64| lba / grain * grain;
+
+        let mut existing: Vec<(u32, &Partition)> =
+            self.partitions().iter().filter(|(_, p)| p.is_used()).map(|(id, p)| (*id, p)).collect();
+        existing.sort_by_key(|(_, p)| p.first_lba);
+
+        // Free areas between partitions, and the partition right before each.
+        let mut areas = Vec::new();
+        let mut cursor = self.first_usable();
+        let mut before = None;
+        for (id, part) in existing.iter().map(|(id, p)| (Some(*id), Some(*p))).chain(Some((None, None))) {
+            let end = part.map_or(self.last_usable() + 1, |p| p.first_lba);
+            let start = align_up(cursor);
+            areas.push(Area { start, len: align_down(end).saturating_sub(start), before, allocated: 0 });
+            if let Some(p) = part {
+                cursor = p.last_lba + 1;
+            }
+            before = id;
+        }
+
+        // Match definitions to existing partitions of the same type, in disk order.
+        let mut matched = vec![false; existing.len()];
+        let mut items = Vec::with_capacity(defs.len());
+        for def in defs {
+            let max = def.size_max_bytes.map_or(u64::max_value(), |m| align_down(m / lb_bytes));
+            let found = existing
+                .iter()
+                .enumerate()
+                .find(|(i, (_, p))| !matched[*i] && p.part_type_guid == def.part_type);
+            let item = match found {
+                Some((i, (id, part))) => {
+                    matched[i] = true;
+                    let area = areas.iter().position(|a| a.before == Some(*id));
+                    // Growing starts at the (aligned) free area, including any slack before it.
+                    let len = area.map_or(part.last_lba + 1, |a| areas[a].start) - part.first_lba;
+                    Item {
+                        existing: Some(*id),
+                        area,
+                        min: 0,
+                        max: align_down(max.saturating_sub(len)),
+                        padding: 0,
+                        size: 0,
+                        dropped: false,
+                    }
+                }
+                None => {
+                    let min = align_up(
+                        (def.size_min_bytes.unwrap_or(DEFAULT_SIZE_MIN_BYTES) + lb_bytes - 1) / lb_bytes,
+                    );
+                    Item {
+                        existing: None,
+                        area: None,
+                        min: min.max(grain),
+                        max: max.max(min.max(grain)),
+                        padding: align_up((def.padding_min_bytes + lb_bytes - 1) / lb_bytes),
+                        size: 0,
+                        dropped: false,
+                    }
+                }
+            };
+            items.push(item);
+        }
+
+        allocate(&mut areas, &mut items, defs)?;
+
+        // Share free space by weight, then lay out each area.
+        let mut partitions = self.partitions().clone();
+        let mut labels: Vec<String> = partitions.values().map(|p| p.name.clone()).collect();
+        let mut results = vec![None; defs.len()];
+        for (a, area) in areas.iter().enumerate() {
+            let mut members: Vec<usize> = (0..items.len()).filter(|i| items[*i].area == Some(a)).collect();
+            // A growing existing partition comes first, right before the area.
+            members.sort_by_key(|i| items[*i].existing.is_none());
+            grow(area.len, &members, &mut items, defs, grain);
+
+            let mut cursor = area.start;
+            for i in members {
+                let item = &items[i];
+                if let Some(id) = item.existing {
+                    if item.size > 0 {
+                        let part = partitions.get_mut(&id).expect("existing partition");
+                        part.last_lba = cursor + item.size - 1;
+                        debug!("growing partition {} ({}) to LBA {}", id, defs[i].name, part.last_lba);
+                    }
+                    cursor += item.size;
+                    continue;
+                }
+                let id = super::find_next_partition_id(&partitions);
+                let mut label = defs[i].label.clone();
+                let mut n = 2;
+                while labels.contains(&label) {
+                    label = format!("{}-{}", defs[i].label, n);
+                    n += 1;
+                }
+                labels.push(label.clone());
+                let part = Partition {
+                    part_type_guid: defs[i].part_type.clone(),
+                    part_guid: defs[i].uuid.unwrap_or_else(uuid::Uuid::new_v4),
+                    first_lba: cursor,
+                    last_lba: cursor + item.size - 1,
+                    flags: defs[i].flags,
+                    name: label,
+                };
+                debug!("adding partition {} ({}) at LBA {}", id, defs[i].name, cursor);
+                partitions.insert(id, part);
+                results[i] = Some(id);
+                cursor += item.size + item.padding;
+            }
+        }
+        for (i, item) in items.iter().enumerate() {
+            if item.existing.is_some() {
+                results[i] = item.existing;
+            }
+        }
+
+        let mut updated = self.clone();
+        updated.update_partitions(partitions);
+        updated.validate()?;
+        *self = updated;
+        Ok(results)
+    }
+}
+
+/// A free area of the disk, in LBAs.
+#[derive(Debug)]
+struct Area {
+    start: u64,
+    len: u64,
+    /// Partition directly before this area, which may grow into it.
+    before: Option<u32>,
+    allocated: u64,
+}
+
+/// Allocation state of a definition. Sizes are in LBAs; for existing
+/// partitions, they only count the growth.
+#[derive(Debug)]
+struct Item {
+    existing: Option<u32>,
+    area: Option<usize>,
+    min: u64,
+    max: u64,
+    padding: u64,
+    size: u64,
+    dropped: bool,
+}
+
+/// Place new partitions in the smallest free area that fits them,
+/// dropping those with the highest priority until all fit.
+fn allocate(areas: &mut [Area], items: &mut [Item], defs: &[Definition]) -> io::Result<()> {
+    let mut by_size: Vec<usize> = (0..areas.len()).collect();
+    by_size.sort_by_key(|a| areas[*a].len);
+    loop {
+        for area in areas.iter_mut() {
+            area.allocated = 0;
+        }
+        let mut fits = true;
+        for item in items.iter_mut().filter(|it| it.existing.is_none()) {
+            item.area = None;
+            if item.dropped {
+                continue;
+            }
+            let need = item.min + item.padding;
+            match by_size.iter().find(|a| areas[**a].len - areas[**a].allocated >= need) {
+                Some(a) => {
+                    areas[*a].allocated += need;
+                    item.area = Some(*a);
+                }
+                None => fits = false,
+            }
+        }
+        if fits {
+            return Ok(());
+        }
+
+        let priority = items
+            .iter()
+            .zip(defs)
+            .filter(|(it, _)| it.existing.is_none() && !it.dropped)
+            .map(|(_, def)| def.priority)
+            .max()
+            .filter(|p| *p > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not enough free space for partitions"))?;
+        for (item, def) in items.iter_mut().zip(defs) {
+            if item.existing.is_none() && def.priority == priority {
+                warn!("dropping partition {} (priority {}) for lack of space", def.name, priority);
+                item.dropped = true;
+            }
+        }
+    }
+}
+
+/// Share `len` LBAs between `members` by weight, within their bounds.
+fn grow(len: u64, members: &[usize], items: &mut [Item], defs: &[Definition], grain: u64) {
+    let mut span = len - members.iter().map(|i| items[*i].padding).sum::<u64>();
+    let mut open: Vec<usize> = Vec::new();
+    for &i in members {
+        if defs[i].weight == 0 {
+            items[i].size = items[i].min;
+            span -= items[i].min;
+        } else {
+            open.push(i);
+        }
+    }
+
+    while !open.is_empty() {
+        let total: u64 = open.iter().map(|i| u64::from(defs[*i].weight)).sum();
+        let share = |i: usize| {
+            (u128::from(span) * u128::from(defs[i].weight) / u128::from(total)) as u64 / grain * grain
+        };
+        // Fix partitions below their minimum first, then those above their maximum.
+        let fixed = open
+            .iter()
+            .position(|i| share(*i) < items[*i].min)
+            .map(|pos| (pos, items[open[pos]].min))
+            .or_else(|| {
+                open.iter()
+                    .position(|i| share(*i) > items[*i].max)
+                    .map(|pos| (pos, items[open[pos]].max))
+            });
+        match fixed {
+            Some((pos, size)) => {
+                let i = open.remove(pos);
+                items[i].size = size;
+                span = span.saturating_sub(size);
+            }
+            None => {
+                let mut left = span;
+                for &i in &open {
+                    items[i].size = share(i);
+                    left -= items[i].size;
+                }
+                // Rounding leftovers go to the last partition that can take them.
+                if let Some(&i) = open.iter().rev().find(|i| items[**i].size < items[**i].max) {
+                    items[i].size = (items[i].size + left / grain * grain).min(items[i].max);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Resolve a symbolic `Type=` name.
+fn type_from_name(name: &str) -> Option<Type> {
+    match name {
+        "esp" => Some(partition_types::EFI),
+        "xbootldr" => Some(partition_types::FREEDESK_BOOT),
+        "swap" => Some(partition_types::LINUX_SWAP),
+        "home" => Some(partition_types::LINUX_HOME),
+        "srv" => Some(partition_types::LINUX_SRV),
+        "linux-generic" => Some(partition_types::LINUX_FS),
+        "root-x86" => Some(partition_types::LINUX_ROOT_X86),
+        "root-x86-64" => Some(partition_types::LINUX_ROOT_X64),
+        "root-arm" => Some(partition_types::LINUX_ROOT_ARM_32),
+        "root-arm64" => Some(partition_types::LINUX_ROOT_ARM_64),
+        _ => None,
+    }
+}
+
+/// Return the symbolic `Type=` name of a partition type, if any.
+fn type_name(part_type: &Type) -> Option<&'static str> {
+    TYPE_NAMES
+        .iter()
+        .find(|name| type_from_name(name).as_ref() == Some(part_type))
+        .cloned()
+}
+
+/// Parse a size with an optional base-1024 suffix, like systemd does.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let factor: u64 = match suffix.trim() {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        "P" => 1 << 50,
+        "E" => 1 << 60,
+        _ => return None,
+    };
+    let mut parts = number.splitn(2, '.');
+    let whole: u64 = parts.next()?.parse().ok()?;
+    let mut size = whole.checked_mul(factor)?;
+    if let Some(frac) = parts.next().filter(|f| !f.is_empty()) {
+        let denom = 10u64.checked_pow(frac.len() as u32)?;
+        let frac: u64 = frac.parse().ok()?;
+        size = size.checked_add((u128::from(frac) * u128::from(factor) / u128::from(denom)) as u64)?;
+    }
+    Some(size)
+}
+
+fn definition_error(name: &str, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("repart definition {}: {}", name, msg))
+}
@@ -0,0 +1,419 @@
+//! Import and export of `sfdisk` scripts.
+//!
+//! The script format is the one printed by `sfdisk --dump` and accepted
+//! on stdin by `sfdisk`: a few `key: value` header lines, an empty line,
+//! then one line of comma-separated `key=value` fields per partition.
+
+use log::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::str::FromStr;
+
+use super::GptTable;
+use crate::disk;
+use crate::partition::{Partition, PartitionAttributes};
+use crate::partition_types;
+
+/// Default number of entries in the partition array (`table-length`).
+const DEFAULT_TABLE_LENGTH: u32 = 128;
+
+/// Attribute names used by sfdisk, for the bits defined by UEFI.
+const ATTRIBUTE_NAMES: [(&str, PartitionAttributes); 3] = [
+    ("RequiredPartition", PartitionAttributes::PLATFORM),
+    ("NoBlockIOProtocol", PartitionAttributes::EFI),
+    ("LegacyBIOSBootable", PartitionAttributes::BOOTABLE),
+];
+
+impl GptTable {
+    /// Serialize this table as an `sfdisk` script, in the same format as
+    /// `sfdisk --dump`.
+    ///
+    /// Partition lines carry no device name, so `sfdisk` numbers them
+    /// sequentially: gaps between partition ids are not kept.
+    pub fn to_sfdisk_script(&self) -> String {
+        let mut script = String::new();
+        script.push_str("label: gpt\n");
+        script.push_str(&format!("label-id: {}\n", format_guid(&self.disk_guid)));
+        script.push_str("unit: sectors\n");
+        script.push_str(&format!("first-lba: {}\n", self.first_usable));
+        script.push_str(&format!("last-lba: {}\n", self.last_usable));
+        script.push_str(&format!("sector-size: {}\n", self.lb_size));
+        if self.num_parts != DEFAULT_TABLE_LENGTH {
+            script.push_str(&format!("table-length: {}\n", self.num_parts));
+        }
+        script.push('\n');
+
+        for part in self.partitions.values().filter(|p| p.is_used()) {
+            script.push_str(&format!(
+                "start={:>12}, size={:>12}, type={}, uuid={}",
+                part.first_lba,
+                part.last_lba - part.first_lba + 1,
+                part.part_type_guid.guid,
+                format_guid(&part.part_guid),
+            ));
+            if !part.name.is_empty() {
+                script.push_str(&format!(", name={}", quote(&part.name)));
+            }
+            if part.flags != 0 {
+                script.push_str(&format!(", attrs={}", quote(&format_attributes(part.flags))));
+            }
+            script.push('\n');
+        }
+        script
+    }
+
+    /// Parse an `sfdisk` script, such as the output of `sfdisk --dump`.
+    ///
+    /// As there is no device to look at, the script must specify the
+    /// `last-lba` header; the backup GPT is assumed to directly follow it.
+    /// Partitions without `start` are placed right after the previous one,
+    /// and partitions without `size` extend to the end of the usable area.
+    /// Missing partition and disk UUIDs are randomly generated.
+    pub fn from_sfdisk_script(script: &str) -> io::Result<Self> {
+        let mut headers: BTreeMap<String, String> = BTreeMap::new();
+        let mut lines = Vec::new();
+        for (n, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !line.contains('=') {
+                let mut kv = line.splitn(2, ':');
+                let key = kv.next().unwrap_or_default().trim();
+                let value = kv
+                    .next()
+                    .ok_or_else(|| script_error(n, "expected 'key: value' header"))?
+                    .trim();
+                headers.insert(key.to_string(), value.to_string());
+            } else {
+                lines.push((n, line));
+            }
+        }
+
+        if let Some(label) = headers.get("label") {
+            if label != "gpt" {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("unsupported sfdisk label type: {}", label),
+                ));
+            }
+        }
+        if let Some(unit) = headers.get("unit") {
+            if unit != "sectors" {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("unsupported sfdisk unit: {}", unit),
+                ));
+            }
+        }
+        let lb_size = match headers.get("sector-size") {
+            Some(v) => disk::LogicalBlockSize::try_from(parse_header_u64("sector-size", v)?)?,
+            None => disk::DEFAULT_SECTOR_SIZE,
+        };
+        let num_parts = match headers.get("table-length") {
+            Some(v) => u32::try_from(parse_header_u64("table-length", v)?)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "invalid sfdisk table-length"))?,
+            None => DEFAULT_TABLE_LENGTH,
+        };
+        let disk_guid = match headers.get("label-id") {
+            Some(v) => Some(parse_guid(v)?),
+            None => None,
+        };
+        let last_lba = headers
+            .get("last-lba")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "sfdisk script has no last-lba"))
+            .and_then(|v| parse_header_u64("last-lba", v))?;
+        for key in headers.keys() {
+            match key.as_str() {
+                "label" | "label-id" | "device" | "unit" | "first-lba" | "last-lba"
+                | "sector-size" | "table-length" | "grain" => {}
+                _ => warn!("ignoring unknown sfdisk header '{}'", key),
+            }
+        }
+
+        let first_lba = match headers.get("first-lba") {
+            Some(v) => Some(parse_header_u64("first-lba", v)?),
+            None => None,
+        };
+        let mut table = table_from_usable(lb_size, disk_guid, num_parts, first_lba, last_lba)?;
+
+        let mut next_id = 1;
+        let mut next_start = table.first_usable;
+        for (n, line) in lines {
+            let (id, part) = parse_partition_line(n, line, next_id, next_start, table.last_usable)?;
+            if table.partitions.insert(id, part.clone()).is_some() {
+                return Err(script_error(n, &format!("duplicate partition {}", id)));
+            }
+            next_id = id + 1;
+            next_start = part.last_lba + 1;
+        }
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+fn parse_partition_line(
+    n: usize,
+    line: &str,
+    default_id: u32,
+    default_start: u64,
+    last_usable: u64,
+) -> io::Result<(u32, Partition)> {
+    // An optional "<device> : " prefix sets the partition number.
+    let (id, fields) = match line.find(':') {
+        Some(pos) if !line[..pos].contains('=') => {
+            let device = line[..pos].trim();
+            let id = partition_id_from_node(device)
+                .ok_or_else(|| script_error(n, &format!("invalid partition device '{}'", device)))?;
+            (id, &line[pos + 1..])
+        }
+        _ => (default_id, line),
+    };
+
+    let mut kvs = Vec::new();
+    for field in split_fields(fields).map_err(|e| script_error(n, e))? {
+        let mut kv = field.splitn(2, '=');
+        let key = kv.next().unwrap_or_default().trim().to_string();
+        let value = unquote(kv.next().unwrap_or_default().trim()).map_err(|e| script_error(n, e))?;
+        kvs.push((key, value));
+    }
+    let part = partition_from_fields(&kvs, default_start, last_usable).map_err(|e| script_error(n, &e))?;
+    Ok((id, part))
+}
+
+/// Build an empty table from its usable area, placing the backup GPT right
+/// after `last_lba` and defaulting `first_lba` to right after the primary
+/// partition array.
+pub(super) fn table_from_usable(
+    lb_size: disk::LogicalBlockSize,
+    disk_guid: Option<uuid::Uuid>,
+    num_parts: u32,
+    first_lba: Option<u64>,
+    last_lba: u64,
+) -> io::Result<GptTable> {
+    let mut table = GptTable {
+        lb_size,
+        disk_guid: disk_guid.unwrap_or_else(uuid::Uuid::new_v4),
+        num_parts,
+        part_size: crate::partition::ENTRY_SIZE as u32,
+        first_usable: 0,
+        last_usable: last_lba,
+        backup_lba: 0,
+        partitions: BTreeMap::new(),
+    };
+    let array_lbs = table.array_lbs()?;
+    table.backup_lba = last_lba
+        .checked_add(array_lbs + 1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "invalid last usable LBA"))?;
+    table.first_usable = first_lba.unwrap_or(2 + array_lbs);
+    if table.first_usable < 2 + array_lbs || table.first_usable > table.last_usable {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "first and last usable LBAs do not fit the partition table",
+        ));
+    }
+    Ok(table)
+}
+
+/// Build a partition from sfdisk `key=value` fields.
+///
+/// Without `start`, the partition begins at `default_start`; without
+/// `size`, it extends to `last_usable`.
+pub(super) fn partition_from_fields(
+    fields: &[(String, String)],
+    default_start: u64,
+    last_usable: u64,
+) -> Result<Partition, String> {
+    let mut part = Partition {
+        part_type_guid: partition_types::LINUX_FS,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba: default_start,
+        last_lba: 0,
+        flags: 0,
+        name: String::new(),
+    };
+    let mut size = None;
+    for (key, value) in fields {
+        match key.as_str() {
+            "start" => {
+                part.first_lba = value.parse().map_err(|_| format!("invalid start '{}'", value))?
+            }
+            "size" => {
+                size = Some(
+                    value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|s| *s > 0)
+                        .ok_or_else(|| format!("invalid size '{}'", value))?,
+                )
+            }
+            "type" => part.part_type_guid = parse_type(value)?,
+            "uuid" => part.part_guid = parse_guid(value).map_err(|e| e.to_string())?,
+            "name" => part.name = value.clone(),
+            "attrs" => part.flags = parse_attributes(value)?,
+            "bootable" => part.flags |= PartitionAttributes::BOOTABLE.bits(),
+            _ => return Err(format!("unknown partition field '{}'", key)),
+        }
+    }
+    part.last_lba = match size {
+        Some(size) => part.first_lba.checked_add(size - 1).ok_or("partition size overflow")?,
+        None => last_usable,
+    };
+    Ok(part)
+}
+
+/// Return the partition number at the end of a device node name, such as
+/// `/dev/sda1` or `/dev/nvme0n1p2`.
+pub(super) fn partition_id_from_node(node: &str) -> Option<u32> {
+    let digits = node.len() - node.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    node[node.len() - digits..].parse::<u32>().ok().filter(|id| *id > 0)
+}
+
+/// Format partition attribute bits as an sfdisk `attrs` value.
+///
+/// Like sfdisk, the type-specific bits 48-63 are listed as `GUID:<bit>`;
+/// other bits without a name are listed by their plain number.
+pub(crate) fn format_attributes(flags: u64) -> String {
+    let mut attrs: Vec<String> = ATTRIBUTE_NAMES
+        .iter()
+        .filter(|(_, attr)| flags & attr.bits() != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    attrs.extend((3..48u32).filter(|bit| flags & (1u64 << bit) != 0).map(|bit| bit.to_string()));
+    let guid_bits: Vec<String> = (48..64u32)
+        .filter(|bit| flags & (1u64 << bit) != 0)
+        .map(|bit| bit.to_string())
+        .collect();
+    if !guid_bits.is_empty() {
+        attrs.push(format!("GUID:{}", guid_bits.join(",")));
+    }
+    attrs.join(" ")
+}
+
+/// Parse an sfdisk `attrs` value into partition attribute bits.
+///
+/// Bits are given by name, by plain number, or as `GUID:<bit>,...`.
+pub(crate) fn parse_attributes(attrs: &str) -> Result<u64, String> {
+    let mut flags = 0;
+    for token in attrs.split_whitespace() {
+        if let Some((_, attr)) = ATTRIBUTE_NAMES.iter().find(|(name, _)| *name == token) {
+            flags |= attr.bits();
+            continue;
+        }
+        let bits = token.trim_start_matches("GUID:");
+        for bit in bits.split(',').filter(|b| !b.is_empty()) {
+            match bit.parse::<u32>() {
+                Ok(bit) if bit < 64 => flags |= 1u64 << bit,
+                _ => return Err(format!("invalid partition attribute '{}'", token)),
+            }
+        }
+    }
+    Ok(flags)
+}
+
+/// Resolve a partition type from a type GUID, a type name or one of the
+/// sfdisk shortcuts.
+///
+/// Unknown type GUIDs are kept as-is, see `Type::from_uuid_or_raw`.
+pub(crate) fn parse_type(value: &str) -> Result<partition_types::Type, String> {
+    let shortcut = match value {
+        "L" => Some(partition_types::LINUX_FS),
+        "S" => Some(partition_types::LINUX_SWAP),
+        "H" => Some(partition_types::LINUX_HOME),
+        "U" => Some(partition_types::EFI),
+        "R" => Some(partition_types::LINUX_RAID),
+        "V" => Some(partition_types::LINUX_LVM),
+        _ => None,
+    };
+    if let Some(t) = shortcut {
+        return Ok(t);
+    }
+    match uuid::Uuid::from_str(value) {
+        Ok(guid) => Ok(partition_types::Type::from_uuid_or_raw(&guid)),
+        Err(_) => partition_types::Type::from_name(value)
+            .map_err(|_| format!("unknown partition type '{}'", value)),
+    }
+}
+
+pub(crate) fn format_guid(guid: &uuid::Uuid) -> String {
+    guid.to_hyphenated().to_string().to_uppercase()
+}
+
+fn parse_guid(value: &str) -> io::Result<uuid::Uuid> {
+    uuid::Uuid::from_str(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("invalid GUID '{}': {}", value, e)))
+}
+
+fn parse_header_u64(key: &str, value: &str) -> io::Result<u64> {
+    value.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::Other, format!("invalid sfdisk {} '{}'", key, value))
+    })
+}
+
+/// Split a partition line on commas outside double quotes.
+fn split_fields(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == ',' {
+            if !current.trim().is_empty() {
+                fields.push(current.trim().to_string());
+            }
+            current.clear();
+            continue;
+        }
+        current.push(c);
+    }
+    if quoted {
+        return Err("unterminated quoted value");
+    }
+    if !current.trim().is_empty() {
+        fields.push(current.trim().to_string());
+    }
+    Ok(fields)
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn unquote(value: &str) -> Result<String, &'static str> {
+    if !value.starts_with('"') {
+        return Ok(value.to_string());
+    }
+    if value.len() < 2 || !value.ends_with('"') {
+        return Err("unterminated quoted value");
+    }
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            unquoted.extend(chars.next());
+        } else {
+            unquoted.push(c);
+        }
+    }
+    Ok(unquoted)
+}
+
+fn script_error(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("sfdisk script line {}: {}", line + 1, msg))
+}
@@ -0,0 +1,219 @@
+//! Import and export of U-Boot `gpt write` partition strings.
+//!
+//! The format is the one of the `$partitions` environment variable passed
+//! to `gpt write`: an optional `uuid_disk=<guid>` entry followed by one
+//! entry per partition, separated by semicolons. Partition entries are
+//! comma-separated `name`, `start`, `size`, `type` and `uuid` fields, plus
+//! an optional `bootable` flag. Offsets and sizes are in bytes, with
+//! optional `K`, `M` or `G` suffixes (`KiB`, `MiB` and `GiB` work too).
+
+use log::*;
+use std::io;
+use std::str::FromStr;
+
+use super::GptTable;
+use crate::disk;
+use crate::partition::{Partition, PartitionAttributes};
+use crate::partition_types;
+
+/// Number of partition entries U-Boot allocates by default.
+const UBOOT_NUM_PARTS: u32 = 128;
+
+/// Partition type names understood by U-Boot in place of a type GUID.
+const TYPE_NAMES: [(&str, partition_types::Type); 10] = [
+    ("system", partition_types::EFI),
+    ("mbr", partition_types::MBR),
+    ("msft", partition_types::MICROSOFT_RESERVED),
+    ("data", partition_types::BASIC),
+    ("linux", partition_types::LINUX_FS),
+    ("raid", partition_types::LINUX_RAID),
+    ("swap", partition_types::LINUX_SWAP),
+    ("lvm", partition_types::LINUX_LVM),
+    ("cros-kern", partition_types::CHROME_KERNEL),
+    ("xbootldr", partition_types::FREEDESK_BOOT),
+];
+
+impl GptTable {
+    /// Serialize this table as a U-Boot `gpt write` partition string.
+    ///
+    /// Every partition gets an explicit `start`, and a last partition
+    /// reaching the end of the usable area is written with `size=-`, so
+    /// the same string fits larger disks too. U-Boot numbers partitions
+    /// in the order they are listed, which is the order of their ids here.
+    /// Attribute bits other than `LegacyBIOSBootable` can't be expressed
+    /// and are dropped with a warning.
+    pub fn to_uboot_partitions(&self) -> io::Result<String> {
+        let lb_size: u64 = self.lb_size.into();
+        let mut entries = vec![format!("uuid_disk={}", self.disk_guid.to_hyphenated())];
+        let used: Vec<&Partition> = self.partitions.values().filter(|p| p.is_used()).collect();
+        for (n, part) in used.iter().enumerate() {
+            if part.name.is_empty() || part.name.contains(&[',', ';', '='][..]) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("partition name '{}' can't be used in a U-Boot string", part.name),
+                ));
+            }
+            let grows = n + 1 == used.len() && part.last_lba == self.last_usable;
+            let size = if grows {
+                "-".to_string()
+            } else {
+                format_size((part.last_lba - part.first_lba + 1) * lb_size)
+            };
+            let mut entry = format!(
+                "name={},start={},size={},type={},uuid={}",
+                part.name,
+                format_size(part.first_lba * lb_size),
+                size,
+                part.part_type_guid.guid.to_lowercase(),
+                part.part_guid.to_hyphenated(),
+            );
+            if part.flags & PartitionAttributes::BOOTABLE.bits() != 0 {
+                entry.push_str(",bootable");
+            }
+            if part.flags & !PartitionAttributes::BOOTABLE.bits() != 0 {
+                warn!("dropping attributes {:#x} of partition '{}'", part.flags, part.name);
+            }
+            entries.push(entry);
+        }
+        Ok(entries.join(";") + ";")
+    }
+
+    /// Parse a U-Boot `gpt write` partition string into a table for a
+    /// disk of `disk_size` bytes.
+    ///
+    /// As with U-Boot, partitions without `start` are placed right after
+    /// the previous one and the last partition may use `size=-` to extend
+    /// to the end of the usable area. Missing partition and disk UUIDs are
+    /// randomly generated, and the type defaults to Linux filesystem data.
+    pub fn from_uboot_partitions(
+        partitions: &str,
+        lb_size: disk::LogicalBlockSize,
+        disk_size: u64,
+    ) -> io::Result<Self> {
+        let mut entries: Vec<&str> = partitions
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .collect();
+        let mut disk_guid = None;
+        if let Some(entry) = entries.first() {
+            if entry.starts_with("uuid_disk=") {
+                disk_guid = Some(parse_guid(entry.trim_start_matches("uuid_disk=")).map_err(uboot_error)?);
+                entries.remove(0);
+            }
+        }
+        let mut table = GptTable::new(lb_size, disk_size, UBOOT_NUM_PARTS, disk_guid)?;
+
+        let mut next_start = table.first_usable;
+        for (n, entry) in entries.iter().enumerate() {
+            let last = n + 1 == entries.len();
+            let part = parse_partition(entry, next_start, last, &table)
+                .map_err(|e| uboot_error(format!("partition {}: {}", n + 1, e)))?;
+            next_start = part.last_lba + 1;
+            table.partitions.insert(n as u32 + 1, part);
+        }
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+/// Build a partition from the fields of one entry, starting at
+/// `default_start` unless it has a `start`. Only the `last` partition may
+/// extend to the end of the usable area with `size=-`.
+fn parse_partition(
+    entry: &str,
+    default_start: u64,
+    last: bool,
+    table: &GptTable,
+) -> Result<Partition, String> {
+    let lb_size: u64 = table.lb_size.into();
+    let mut part = Partition {
+        part_type_guid: partition_types::LINUX_FS,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba: default_start,
+        last_lba: 0,
+        flags: 0,
+        name: String::new(),
+    };
+    let mut size = None;
+    for field in entry.split(',').map(str::trim) {
+        if field == "bootable" {
+            part.flags |= PartitionAttributes::BOOTABLE.bits();
+            continue;
+        }
+        let mut kv = field.splitn(2, '=');
+        let key = kv.next().unwrap_or_default();
+        let value = kv.next().ok_or_else(|| format!("expected 'key=value', got '{}'", field))?;
+        match key {
+            "name" => part.name = value.to_string(),
+            "start" => part.first_lba = parse_size(value, lb_size)?,
+            "size" if value == "-" && last => size = Some(None),
+            "size" if value == "-" => return Err("only the last partition can use size=-".into()),
+            "size" => size = Some(Some(parse_size(value, lb_size)?)),
+            "type" => part.part_type_guid = parse_type(value)?,
+            "uuid" => part.part_guid = parse_guid(value)?,
+            _ => warn!("ignoring unknown U-Boot partition field '{}'", key),
+        }
+    }
+    if part.name.is_empty() {
+        return Err("missing name".to_string());
+    }
+    part.last_lba = match size.ok_or("missing size")? {
+        None => table.last_usable,
+        Some(0) => return Err("empty size".to_string()),
+        Some(size) => part.first_lba.checked_add(size - 1).ok_or("partition size overflow")?,
+    };
+    Ok(part)
+}
+
+/// Parse a byte count with an optional binary suffix into a number of
+/// logical blocks.
+fn parse_size(value: &str, lb_size: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}'", value);
+    let digits = value.trim_end_matches("iB");
+    let (digits, shift) = match digits.chars().last() {
+        Some('K') | Some('k') => (&digits[..digits.len() - 1], 10),
+        Some('M') | Some('m') => (&digits[..digits.len() - 1], 20),
+        Some('G') | Some('g') => (&digits[..digits.len() - 1], 30),
+        _ if digits.len() == value.len() => (digits, 0),
+        _ => return Err(invalid()),
+    };
+    let bytes = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse::<u64>()
+    }
+    .map_err(|_| invalid())?;
+    let bytes = bytes.checked_mul(1u64 << shift).ok_or_else(invalid)?;
+    if bytes % lb_size != 0 {
+        return Err(format!("'{}' is not a multiple of the {} bytes block size", value, lb_size));
+    }
+    Ok(bytes / lb_size)
+}
+
+/// Format a byte count with the largest binary suffix that divides it.
+fn format_size(bytes: u64) -> String {
+    for (suffix, shift) in &[("GiB", 30u32), ("MiB", 20), ("KiB", 10)] {
+        if bytes != 0 && bytes % (1u64 << shift) == 0 {
+            return format!("{}{}", bytes >> shift, suffix);
+        }
+    }
+    bytes.to_string()
+}
+
+/// Resolve a partition type from a type GUID or one of U-Boot's names.
+fn parse_type(value: &str) -> Result<partition_types::Type, String> {
+    if let Some((_, t)) = TYPE_NAMES.iter().find(|(name, _)| *name == value) {
+        return Ok(t.clone());
+    }
+    let guid = parse_guid(value)?;
+    partition_types::Type::from_uuid(&guid).map_err(|_| format!("unknown partition type GUID {}", value))
+}
+
+fn parse_guid(value: &str) -> Result<uuid::Uuid, String> {
+    uuid::Uuid::from_str(value).map_err(|e| format!("invalid GUID '{}': {}", value, e))
+}
+
+fn uboot_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("U-Boot partition string: {}", msg))
+}
@@ -0,0 +1,157 @@
+//! Helpers for testing code built on top of this crate.
+//!
+//! This module is only available with the `testing` cargo feature.
+
+use crate::disk;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+/// A memory device which injects faults, for crash-consistency testing.
+///
+/// It wraps a `Cursor<Vec<u8>>` and, depending on configuration, fails
+/// a given write, silently truncates it, or "loses power" after a given
+/// number of bytes, after which all I/O fails. Sectors can also be
+/// corrupted in place with `flip_bits`.
+#[derive(Debug)]
+pub struct FaultyDevice {
+    inner: Cursor<Vec<u8>>,
+    writes: usize,
+    bytes_written: u64,
+    fail_write: Option<usize>,
+    truncate_write: Option<(usize, usize)>,
+    power_loss_after: Option<u64>,
+    powered_off: bool,
+}
+
+impl FaultyDevice {
+    /// Create a device holding `data`, without any fault configured.
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            inner: Cursor::new(data),
+            writes: 0,
+            bytes_written: 0,
+            fail_write: None,
+            truncate_write: None,
+            power_loss_after: None,
+            powered_off: false,
+        }
+    }
+
+    /// Fail the `n`-th write call (starting from 1), leaving data untouched.
+    pub fn fail_nth_write(mut self, n: usize) -> Self {
+        self.fail_write = Some(n);
+        self
+    }
+
+    /// Only store the first `len` bytes of the `n`-th write call (starting
+    /// from 1), while still reporting it as fully written.
+    pub fn truncate_nth_write(mut self, n: usize, len: usize) -> Self {
+        self.truncate_write = Some((n, len));
+        self
+    }
+
+    /// Lose power once `bytes` bytes have been written: the write crossing
+    /// this limit is cut short, and all following I/O fails.
+    ///
+    /// A write ending exactly at the limit is fully stored and succeeds,
+    /// so the power loss is only noticed by the next I/O call.
+    pub fn lose_power_after(mut self, bytes: u64) -> Self {
+        self.power_loss_after = Some(bytes);
+        self
+    }
+
+    /// Flip the bits in `mask` for every byte of sector `lba`.
+    pub fn flip_bits(&mut self, lba: u64, lb_size: disk::LogicalBlockSize, mask: u8) {
+        let lb_bytes: usize = lb_size.into();
+        let start = (lba as usize).saturating_mul(lb_bytes);
+        let data = self.inner.get_mut();
+        let end = start.saturating_add(lb_bytes).min(data.len());
+        for b in data.iter_mut().take(end).skip(start) {
+            *b ^= mask;
+        }
+    }
+
+    /// Return the number of write calls so far.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Return the number of bytes actually stored so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Return whether power was lost.
+    pub fn powered_off(&self) -> bool {
+        self.powered_off
+    }
+
+    /// Return the current device content.
+    pub fn get_ref(&self) -> &Vec<u8> {
+        self.inner.get_ref()
+    }
+
+    /// Unwrap this device, returning its content.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.inner.into_inner()
+    }
+
+    fn check_power(&self) -> io::Result<()> {
+        if self.powered_off {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected fault: power lost"));
+        }
+        Ok(())
+    }
+}
+
+impl Read for FaultyDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_power()?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultyDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_power()?;
+        self.writes += 1;
+        if self.fail_write == Some(self.writes) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("injected fault: write #{} failed", self.writes),
+            ));
+        }
+        let mut len = buf.len();
+        if let Some((n, truncated)) = self.truncate_write {
+            if n == self.writes {
+                len = len.min(truncated);
+            }
+        }
+        if let Some(limit) = self.power_loss_after {
+            let left = limit.saturating_sub(self.bytes_written);
+            if (len as u64) >= left {
+                len = left as usize;
+                self.powered_off = true;
+            }
+        }
+        let stored = self.inner.write(&buf[..len])?;
+        self.bytes_written += stored as u64;
+        if self.powered_off && stored < buf.len() {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected fault: power lost"));
+        }
+        // Truncated writes are reported as complete.
+        self.inner.seek(SeekFrom::Current((buf.len() - stored) as i64))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_power()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyDevice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.check_power()?;
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,259 @@
+use gpt::device::AlignedDevice;
+use gpt::disk;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+/// A memory device rejecting any I/O which is not full, aligned sectors.
+#[derive(Debug)]
+struct StrictDevice {
+    inner: Cursor<Vec<u8>>,
+    lb_size: usize,
+}
+
+impl StrictDevice {
+    fn check(&self, buf: &[u8]) -> std::io::Result<()> {
+        let aligned = self.inner.position() as usize % self.lb_size == 0
+            && buf.len() % self.lb_size == 0
+            && buf.as_ptr() as usize % self.lb_size == 0;
+        if !aligned {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unaligned I/O"));
+        }
+        Ok(())
+    }
+}
+
+impl Read for StrictDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check(buf)?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for StrictDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check(buf)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for StrictDevice {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// A memory device recording the offset and length of every write.
+#[derive(Debug)]
+struct WriteLog {
+    inner: Cursor<Vec<u8>>,
+    writes: Vec<(u64, usize)>,
+}
+
+impl Read for WriteLog {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for WriteLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes.push((self.inner.position(), buf.len()));
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for WriteLog {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn test_aligned_device() {
+    for lb_size in &[disk::LogicalBlockSize::Lb512, disk::LogicalBlockSize::Lb4096] {
+        let lb_bytes: usize = (*lb_size).into();
+        let strict = StrictDevice { inner: Cursor::new(vec![0u8; 2 * 1024 * 1024]), lb_size: lb_bytes };
+        let device = AlignedDevice::new(strict, *lb_size);
+        let options = gpt::CreateOptions { lb_size: *lb_size, entries: 16, ..Default::default() };
+        let mut gdisk = gpt::GptDisk::create(device, options).unwrap();
+        gdisk.add_partition("data", 64 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+        let mut device = gdisk.write().unwrap();
+
+        // Sub-sector MBR edits are read-modify-write cycles.
+        gpt::mbr::write_bootcode(&mut device, &[0xAB; 440]).unwrap();
+        gpt::mbr::write_disk_signature(&mut device, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(gpt::mbr::read_disk_signature(&mut device).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(gpt::mbr::read_bootcode(&mut device).unwrap()[..], [0xAB; 440][..]);
+
+        let mut gdisk = gpt::GptConfig::new()
+            .writable(true)
+            .logical_block_size_auto()
+            .open_from_device(device)
+            .unwrap();
+        assert_eq!(gdisk.logical_block_size(), lb_size);
+        assert_eq!(gdisk.partitions().len(), 1);
+        gdisk.remove_partition(Some(1), None).unwrap();
+        gdisk.write_inplace().unwrap();
+
+        // Unaligned access without the wrapper is refused.
+        let mut strict = gdisk.into_inner().into_inner();
+        strict.seek(SeekFrom::Start(440)).unwrap();
+        strict.write_all(&[0; 4]).unwrap_err();
+    }
+}
+
+#[test]
+fn test_write_whole_sectors() {
+    let lb_size = disk::LogicalBlockSize::Lb4096;
+    let options = gpt::CreateOptions { lb_size, ..Default::default() };
+    let gdisk = gpt::GptDisk::create(Cursor::new(vec![0u8; 2 * 1024 * 1024]), options).unwrap();
+    let device = WriteLog { inner: gdisk.into_inner(), writes: Vec::new() };
+
+    // Primary-only writes also rewrite the protective MBR.
+    let mut gdisk = gpt::GptConfig::new()
+        .writable(true)
+        .logical_block_size(lb_size)
+        .skip_backup(true)
+        .open_from_device(device)
+        .unwrap();
+    gdisk.add_partition("data", 64 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    gdisk.write_inplace().unwrap();
+    let device = gdisk.into_inner();
+    assert!(device.writes.contains(&(0, 4096)));
+    for (offset, len) in &device.writes {
+        assert!(offset % 4096 == 0 && len % 4096 == 0, "{} bytes written at {}", len, offset);
+    }
+}
+
+#[test]
+fn test_aligned_device_partial_io() {
+    let mut device = AlignedDevice::new(Cursor::new(vec![0u8; 4096]), disk::LogicalBlockSize::Lb512);
+    // A write straddling three sectors keeps surrounding bytes intact.
+    device.get_mut().get_mut()[500] = 0xEE;
+    device.get_mut().get_mut()[1100] = 0xFF;
+    device.seek(SeekFrom::Start(510)).unwrap();
+    device.write_all(&[0x11; 520]).unwrap();
+    let data = device.get_ref().get_ref();
+    assert_eq!(data[500], 0xEE);
+    assert!(data[510..1030].iter().all(|b| *b == 0x11));
+    assert_eq!(data[1100], 0xFF);
+
+    // Reads are clamped at the end of the device.
+    device.seek(SeekFrom::End(-10)).unwrap();
+    let mut buf = [0u8; 100];
+    assert_eq!(device.read(&mut buf).unwrap(), 10);
+    assert_eq!(device.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_partition_device() {
+    let mut gdisk = gpt::GptDisk::create(Cursor::new(vec![0u8; 1024 * 1024]), Default::default()).unwrap();
+    let id = gdisk.add_partition("data", 4 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let part = gdisk.partitions()[&id].clone();
+    assert_eq!(part.bytes_len(disk::DEFAULT_SECTOR_SIZE).unwrap(), 4 * 512);
+
+    {
+        let mut pdev = gdisk.partition_device(id).unwrap();
+        assert_eq!(pdev.seek(SeekFrom::End(0)).unwrap(), 4 * 512);
+        pdev.seek(SeekFrom::Start(0)).unwrap();
+        pdev.write_all(&[0x5A; 4 * 512]).unwrap();
+
+        // I/O past the end of the partition is refused.
+        pdev.write_all(&[0x5A]).unwrap_err();
+        pdev.seek(SeekFrom::Start(4 * 512 - 1)).unwrap();
+        pdev.write_all(&[0x5A; 2]).unwrap_err();
+        let mut buf = [0u8; 16];
+        assert_eq!(pdev.read(&mut buf).unwrap(), 1);
+        assert_eq!(pdev.read(&mut buf).unwrap(), 0);
+    }
+    gdisk.partition_device(id + 1).unwrap_err();
+
+    // Only the partition sectors were written, last one included.
+    let start = part.bytes_start(disk::DEFAULT_SECTOR_SIZE).unwrap() as usize;
+    let data = gdisk.device().get_ref();
+    assert_eq!(data[start - 1], 0);
+    assert!(data[start..start + 4 * 512].iter().all(|b| *b == 0x5A));
+    assert_eq!(data[start + 4 * 512], 0);
+}
+
+#[test]
+fn test_offset_device_nested_gpt() {
+    let mut outer = gpt::GptDisk::create(Cursor::new(vec![0u8; 4 * 1024 * 1024]), Default::default()).unwrap();
+    let id = outer.add_partition("vm", 1024 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    outer.write_inplace().unwrap();
+
+    // Create a full GPT disk inside the partition...
+    {
+        let pdev = outer.partition_device(id).unwrap();
+        let mut inner = gpt::GptDisk::create(pdev, Default::default()).unwrap();
+        inner.add_partition("root", 256 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+        inner.write_inplace().unwrap();
+        assert_eq!(inner.primary_header().unwrap().backup_lba, 2047);
+    }
+
+    // ...and open it again, without disturbing the outer one.
+    let inner = outer.open_nested(id, gpt::GptConfig::new()).unwrap();
+    assert_eq!(inner.partitions()[&1].name, "root");
+    let mut device = outer.into_inner();
+    device.set_position(0);
+    let outer = gpt::GptConfig::new().open_from_device(device).unwrap();
+    assert_eq!(outer.partitions()[&id].name, "vm");
+}
+
+#[test]
+fn test_offset_device_container_header() {
+    let gdisk = gpt::GptDisk::create(Cursor::new(vec![0u8; 1024 * 1024]), Default::default()).unwrap();
+    let mut image = vec![0xC0; 4096];
+    image.extend_from_slice(gdisk.into_inner().get_ref());
+
+    let mut device = gpt::device::OffsetDevice::from_offset(Cursor::new(image), 4096);
+    assert_eq!(device.seek(SeekFrom::End(0)).unwrap(), 1024 * 1024);
+    let gdisk = gpt::GptConfig::new().open_from_device(device).unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().backup_lba, 2047);
+    assert_eq!(gdisk.into_inner().into_inner().get_ref()[..4096], [0xC0; 4096][..]);
+}
+
+#[test]
+fn test_overlay_device() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let mut base = Cursor::new(image.clone());
+    let mut overlay = gpt::device::OverlayDevice::new(&mut base);
+
+    // Preview an edit: the base device is not touched.
+    let mut gdisk = gpt::GptConfig::new().writable(true).open_from_device(&mut overlay).unwrap();
+    gdisk.remove_partition(Some(1), None).unwrap();
+    gdisk.write_inplace().unwrap();
+    drop(gdisk);
+    assert!(overlay.is_modified());
+    assert_eq!(overlay.get_ref().get_ref(), &image);
+    let preview = gpt::GptConfig::new().open_from_device(&mut overlay).unwrap();
+    assert!(preview.partitions().is_empty());
+    drop(preview);
+
+    // Discarding restores the original view.
+    overlay.discard();
+    let gdisk = gpt::GptConfig::new().open_from_device(&mut overlay).unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+    drop(gdisk);
+
+    // Writes past the end grow the overlay, and are committed at once.
+    overlay.seek(SeekFrom::Start(image.len() as u64 + 10)).unwrap();
+    overlay.write_all(b"tail").unwrap();
+    assert_eq!(overlay.seek(SeekFrom::End(0)).unwrap(), image.len() as u64 + 14);
+    overlay.seek(SeekFrom::Start(446)).unwrap();
+    overlay.write_all(&[0u8; 16]).unwrap();
+    overlay.commit().unwrap();
+    assert!(!overlay.is_modified());
+    let data = base.get_ref();
+    assert_eq!(data.len(), image.len() + 14);
+    assert_eq!(&data[446..462], &[0u8; 16]);
+    assert_eq!(&data[image.len() + 10..], b"tail");
+    assert_eq!(&data[..446], &image[..446]);
+}
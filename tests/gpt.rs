@@ -3,7 +3,7 @@ use gpt;
 use gpt::disk;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::io::{SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path;
 use tempfile::NamedTempFile;
 
@@ -53,7 +53,7 @@ fn test_gptdisk_linux_01() {
     let p1_start = p1.bytes_start(*gdisk.logical_block_size()).unwrap();
     assert_eq!(p1_start, 0x22 * 512);
     let p1_len = p1.bytes_len(*gdisk.logical_block_size()).unwrap();
-    assert_eq!(p1_len, (0x3E - 0x22) * 512);
+    assert_eq!(p1_len, (0x3E - 0x22 + 1) * 512);
 }
 
 #[test]
@@ -61,7 +61,10 @@ fn test_gptdisk_linux_01_write_fidelity_with_device() {
     let diskpath = path::Path::new("tests/fixtures/gpt-linux-disk-01.img");
 
     // Assumes that test_gptdisk_linux_01 has passed, no need to check answers.
-    let mut gdisk = gpt::GptConfig::new().open(diskpath).unwrap();
+    let file = std::fs::File::open(diskpath).unwrap();
+    let mut gdisk = gpt::GptConfig::new()
+        .open_from_device(Box::new(file) as gpt::DiskDeviceObject)
+        .unwrap();
     let good_header1 = gdisk.primary_header().unwrap().clone();
     let good_header2 = gdisk.backup_header().unwrap().clone();
     let good_partitions = gdisk.partitions().clone();
@@ -128,7 +131,7 @@ fn test_create_simple_on_device() {
     mem_device.read_exact(&mut final_bytes).unwrap();
 }
 
-fn t_read_bytes(device: &mut gpt::DiskDeviceObject, offset: u64, bytes: usize) -> Vec<u8> {
+fn t_read_bytes<D: gpt::DiskDevice>(device: &mut D, offset: u64, bytes: usize) -> Vec<u8> {
     let mut buf = vec![0u8; bytes];
     device.seek(std::io::SeekFrom::Start(offset)).unwrap();
     device.read_exact(&mut buf).unwrap();
@@ -212,3 +215,1020 @@ fn test_gptdisk_write_efi_unused_partition_entries_512() {
 fn test_gptdisk_write_efi_unused_partition_entries_4096() {
     test_helper_gptdisk_write_efi_unused_partition_entries(disk::LogicalBlockSize::Lb4096);
 }
+
+#[test]
+fn test_gptdisk_write_efi_unused_partition_entries_1024() {
+    test_helper_gptdisk_write_efi_unused_partition_entries(disk::LogicalBlockSize::Lb1024);
+}
+
+#[test]
+fn test_gptdisk_write_efi_unused_partition_entries_2048() {
+    test_helper_gptdisk_write_efi_unused_partition_entries(disk::LogicalBlockSize::Lb2048);
+}
+
+#[test]
+fn test_gptdisk_write_efi_unused_partition_entries_8192() {
+    test_helper_gptdisk_write_efi_unused_partition_entries(disk::LogicalBlockSize::Lb8192);
+}
+
+fn t_blank_gpt_image(lb_size: disk::LogicalBlockSize, total_bytes: usize) -> Vec<u8> {
+    let mut mem_device = Box::new(std::io::Cursor::new(vec![0u8; total_bytes]));
+    let lb_bytes: u64 = lb_size.into();
+    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+        u32::try_from((total_bytes as u64 / lb_bytes) - 1).unwrap_or(0xFF_FF_FF_FF));
+    mbr.overwrite_lba0(&mut mem_device).unwrap();
+
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .logical_block_size(lb_size)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    gdisk.update_partitions(BTreeMap::<u32, gpt::partition::Partition>::new()).unwrap();
+    gdisk.add_partition("test1", 4 * lb_bytes, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let mut mem_device = gdisk.write().unwrap();
+    t_read_bytes(&mut mem_device, 0, total_bytes)
+}
+
+#[test]
+fn test_gptconfig_lb_size_auto() {
+    // Known 512-bytes fixture.
+    let diskpath = path::Path::new("tests/fixtures/gpt-linux-disk-01.img");
+    let gdisk = gpt::GptConfig::new().logical_block_size_auto().open(diskpath).unwrap();
+    assert_eq!(*gdisk.logical_block_size(), disk::LogicalBlockSize::Lb512);
+
+    for lb_size in &[disk::LogicalBlockSize::Lb2048, disk::LogicalBlockSize::Lb4096] {
+        let image = t_blank_gpt_image(*lb_size, 1024 * 1024);
+        let mem_device = Box::new(std::io::Cursor::new(image));
+
+        // A wrongly-sized open fails...
+        gpt::GptConfig::new()
+            .open_from_device(Box::new(mem_device.clone()))
+            .unwrap_err();
+
+        // ...while detection finds the right size.
+        let gdisk = gpt::GptConfig::new()
+            .logical_block_size_auto()
+            .open_from_device(mem_device)
+            .unwrap();
+        assert_eq!(gdisk.logical_block_size(), lb_size);
+        assert_eq!(gdisk.partitions().len(), 1);
+    }
+}
+
+#[test]
+fn test_gptconfig_lb_size_auto_ambiguous() {
+    const TOTAL_BYTES: usize = 1024 * 1024;
+    let image512 = t_blank_gpt_image(disk::LogicalBlockSize::Lb512, TOTAL_BYTES);
+    let mut image = t_blank_gpt_image(disk::LogicalBlockSize::Lb4096, TOTAL_BYTES);
+
+    // Plant a valid 512-bytes primary header in the 4096-bytes LBA0.
+    image[512..1024].copy_from_slice(&image512[512..1024]);
+    let mut mem_device = std::io::Cursor::new(image);
+    disk::detect_logical_block_size(&mut mem_device).unwrap_err();
+
+    // No GPT at all.
+    let mut mem_device = std::io::Cursor::new(vec![0u8; TOTAL_BYTES]);
+    disk::detect_logical_block_size(&mut mem_device).unwrap_err();
+
+    // Sector sizes above 64 KiB are not probed.
+    let mut image = vec![0u8; TOTAL_BYTES];
+    image[128 * 1024..128 * 1024 + 512].copy_from_slice(&image512[512..1024]);
+    let mut mem_device = std::io::Cursor::new(image);
+    disk::detect_logical_block_size(&mut mem_device).unwrap_err();
+}
+
+fn t_part(name: &str, first_lba: u64, last_lba: u64) -> gpt::partition::Partition {
+    gpt::partition::Partition {
+        part_type_guid: gpt::partition_types::LINUX_FS,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba,
+        last_lba,
+        flags: 0,
+        name: name.to_string(),
+    }
+}
+
+#[test]
+fn test_gptdisk_convert_block_size() {
+    const TOTAL_BYTES: usize = 4 * 1024 * 1024;
+    let mut mem_device = Box::new(std::io::Cursor::new(vec![0u8; TOTAL_BYTES]));
+    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(u32::try_from((TOTAL_BYTES / 512) - 1).unwrap());
+    mbr.overwrite_lba0(&mut mem_device).unwrap();
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    let mut partitions = BTreeMap::new();
+    partitions.insert(1, t_part("p1", 2048, 4095));
+    partitions.insert(2, t_part("p2", 4096, 6143));
+    gdisk.update_partitions(partitions).unwrap();
+    let mem_device = gdisk.write().unwrap();
+
+    // 512 -> 4096.
+    let mut gdisk = gpt::GptConfig::new().writable(true).open_from_device(mem_device).unwrap();
+    let before = gdisk.partitions().clone();
+    gdisk.convert_block_size(disk::LogicalBlockSize::Lb4096).unwrap();
+    let mut mem_device = gdisk.write().unwrap();
+
+    let pmbr = gpt::mbr::ProtectiveMBR::from_disk(&mut mem_device, disk::LogicalBlockSize::Lb4096).unwrap();
+    assert_eq!(pmbr.partition(0).unwrap().lb_size, u32::try_from((TOTAL_BYTES / 4096) - 1).unwrap());
+    // Old 512-bytes primary header has been wiped, so detection is not ambiguous.
+    assert_eq!(disk::detect_logical_block_size(&mut mem_device).unwrap(), disk::LogicalBlockSize::Lb4096);
+
+    let mut gdisk = gpt::GptConfig::new()
+        .writable(true)
+        .logical_block_size(disk::LogicalBlockSize::Lb4096)
+        .open_from_device(mem_device)
+        .unwrap();
+    let h1 = gdisk.primary_header().unwrap();
+    assert_eq!(h1.backup_lba, (TOTAL_BYTES / 4096) as u64 - 1);
+    assert_eq!(h1.first_usable, 6);
+    assert_eq!(h1.num_parts, 128);
+    for (id, part) in gdisk.partitions() {
+        let orig = &before[id];
+        assert_eq!(part.part_guid, orig.part_guid);
+        assert_eq!(
+            part.bytes_start(disk::LogicalBlockSize::Lb4096).unwrap(),
+            orig.bytes_start(disk::LogicalBlockSize::Lb512).unwrap()
+        );
+        assert_eq!((part.last_lba + 1) * 4096, (orig.last_lba + 1) * 512);
+    }
+
+    // 4096 -> 512 round-trips back to the original layout.
+    gdisk.convert_block_size(disk::LogicalBlockSize::Lb512).unwrap();
+    let mut mem_device = gdisk.write().unwrap();
+    assert_eq!(disk::detect_logical_block_size(&mut mem_device).unwrap(), disk::LogicalBlockSize::Lb512);
+    let gdisk = gpt::GptConfig::new().open_from_device(mem_device).unwrap();
+    assert_eq!(gdisk.partitions(), &before);
+}
+
+#[test]
+fn test_gptdisk_convert_block_size_unaligned() {
+    const TOTAL_BYTES: usize = 1024 * 1024;
+    let mem_device = Box::new(std::io::Cursor::new(vec![0u8; TOTAL_BYTES]));
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    let mut partitions = BTreeMap::new();
+    partitions.insert(1, t_part("p1", 2049, 2056));
+    gdisk.update_partitions(partitions).unwrap();
+    gdisk.convert_block_size(disk::LogicalBlockSize::Lb4096).unwrap_err();
+    assert_eq!(*gdisk.logical_block_size(), disk::LogicalBlockSize::Lb512);
+    assert_eq!(gdisk.partitions()[&1].first_lba, 2049);
+}
+
+#[test]
+fn test_gptdisk_concrete_device() {
+    const TOTAL_BYTES: usize = 1024 * 64;
+    let mem_device = std::io::Cursor::new(vec![0u8; TOTAL_BYTES]);
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    assert_eq!(gdisk.device().get_ref().len(), TOTAL_BYTES);
+    gdisk.update_partitions(BTreeMap::<u32, gpt::partition::Partition>::new()).unwrap();
+    gdisk.add_partition("test1", 1024 * 12, gpt::partition_types::BASIC, 0).unwrap();
+    gdisk.write_inplace().unwrap();
+    gdisk.device_mut().set_position(0);
+
+    // The concrete buffer is handed back, no trait object involved.
+    let buf: Vec<u8> = gdisk.into_inner().into_inner();
+    assert_eq!(buf.len(), TOTAL_BYTES);
+    let gdisk = gpt::GptConfig::new().open_from_device(std::io::Cursor::new(buf)).unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+}
+
+#[test]
+fn test_gpttable_offline_layout() {
+    const TOTAL_BYTES: u64 = 1024 * 1024;
+    let lb_size = disk::LogicalBlockSize::Lb512;
+    let mut table = gpt::table::GptTable::new(lb_size, TOTAL_BYTES, 128, None).unwrap();
+    assert_eq!(table.first_usable(), 34);
+    assert_eq!(table.last_usable(), 2047 - 33);
+    assert_eq!(table.find_free_sectors(), vec![(34, 2047 - 33 - 34 + 1)]);
+
+    // Layout operations work without any device.
+    let p1 = table.add_partition("p1", 100 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let p2 = table.add_partition("p2", 10 * 512, gpt::partition_types::EFI, 0).unwrap();
+    assert_eq!((p1, p2), (1, 2));
+    assert_eq!(table.partitions()[&2].first_lba, 134);
+    let snapshot = table.clone();
+    table.remove_partition(Some(1), None).unwrap();
+    assert_ne!(table, snapshot);
+    assert_eq!(table.find_free_sectors(), vec![(34, 100), (144, 2014 - 144 + 1)]);
+    assert_eq!(table.find_next_partition_id(), 1);
+    table.validate().unwrap();
+
+    // Exact-fit gaps are usable, and a full disk has no free space.
+    let p3 = table.add_partition("p3", 100 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    assert_eq!(table.partitions()[&p3].first_lba, 34);
+    let rest = table.find_free_sectors()[0].1;
+    table.add_partition("p4", rest * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    assert!(table.find_free_sectors().is_empty());
+    table.validate().unwrap();
+
+    // Bind to a device only when writing.
+    let mem_device = std::io::Cursor::new(vec![0u8; TOTAL_BYTES as usize]);
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    gdisk.update_table(table.clone()).unwrap();
+    let mut mem_device = gdisk.write().unwrap();
+    mem_device.set_position(0);
+    let gdisk = gpt::GptConfig::new().open_from_device(mem_device).unwrap();
+    let reread = gdisk.table().unwrap();
+    assert_eq!(reread.guid(), table.guid());
+    assert_eq!(reread.partitions().len(), 3);
+    assert_eq!(reread.primary_header().unwrap(), *gdisk.primary_header().unwrap());
+    assert_eq!(reread.backup_header().unwrap(), *gdisk.backup_header().unwrap());
+}
+
+#[test]
+fn test_gpttable_exact_allocation() {
+    let mut table = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, 1024 * 1024, 128, None)
+        .unwrap();
+    // Free space runs up to and including the last usable LBA.
+    assert_eq!(table.find_free_sectors(), vec![(34, table.last_usable() - 34 + 1)]);
+
+    // A gap one LBA too small is skipped instead of overlapping the next partition.
+    table.add_partition("p1", 100 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    table.add_partition("p2", 10 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    table.remove_partition(Some(1), None).unwrap();
+    let p3 = table.add_partition("p3", 101 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    assert_eq!(table.partitions()[&p3].first_lba, 144);
+    table.validate().unwrap();
+
+    // Sizes below one logical block are rejected.
+    table.add_partition("p4", 0, gpt::partition_types::LINUX_FS, 0).unwrap_err();
+    table.add_partition("p4", 511, gpt::partition_types::LINUX_FS, 0).unwrap_err();
+
+    // Partitions ending on the last usable LBA leave no free space behind.
+    let rest = table.find_free_sectors().last().unwrap().1;
+    let p4 = table.add_partition("p4", rest * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    assert_eq!(table.partitions()[&p4].last_lba, table.last_usable());
+    assert_eq!(table.find_free_sectors(), vec![(34, 100)]);
+}
+
+#[test]
+fn test_gpttable_validate_metadata() {
+    let lb_size = disk::LogicalBlockSize::Lb512;
+    let table = gpt::table::GptTable::new(lb_size, 1024 * 1024, 128, None).unwrap();
+    let good = table.primary_header().unwrap();
+    let rebuild = |h: &gpt::header::Header| {
+        gpt::table::GptTable::from_header(h, BTreeMap::new(), lb_size).validate()
+    };
+    rebuild(&good).unwrap();
+
+    // First usable LBA inside the primary partition array.
+    let mut h = good.clone();
+    h.first_usable = 33;
+    rebuild(&h).unwrap_err();
+
+    // Last usable LBA inside the backup partition array.
+    let mut h = good.clone();
+    h.last_usable = h.backup_lba - 32;
+    rebuild(&h).unwrap_err();
+
+    // Empty partition array.
+    let mut h = good.clone();
+    h.num_parts = 0;
+    rebuild(&h).unwrap_err();
+
+    // Partition array larger than the default limit.
+    let mut h = good.clone();
+    h.num_parts = 8193;
+    h.first_usable = 2 + 8193 * 128 / 512 + 1;
+    rebuild(&h).unwrap_err();
+}
+
+#[test]
+fn test_gpttable_update_table_mismatch() {
+    let table = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb4096, 1024 * 1024, 128, None)
+        .unwrap();
+    let mem_device = std::io::Cursor::new(vec![0u8; 1024 * 1024]);
+    let mut gdisk = gpt::GptConfig::default()
+        .initialized(false)
+        .writable(true)
+        .create_from_device(mem_device, None)
+        .unwrap();
+    gdisk.update_table(table).unwrap_err();
+
+    // Tables larger than the device are rejected too.
+    let table = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, 2 * 1024 * 1024, 128, None)
+        .unwrap();
+    gdisk.update_table(table).unwrap_err();
+}
+
+#[test]
+fn test_gpttable_sfdisk_script() {
+    // Output of `sfdisk --dump` for a 1 MiB image.
+    let dump = "label: gpt
+label-id: 2D3B7F4B-1D2B-4C4B-9A8A-6E1F0C6A9B11
+device: /dev/loop0
+unit: sectors
+first-lba: 34
+last-lba: 2014
+sector-size: 512
+
+/dev/loop0p1 : start=          34, size=         100, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=6A1C2E34-7B59-4D1A-8E4F-0C9D3B2A1F10, name=\"EFI \\\"boot\\\"\", attrs=\"RequiredPartition LegacyBIOSBootable GUID:60,63\"
+/dev/loop0p3 : start=         134, size=        1881, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, uuid=9E0B1F7A-2C3D-4E5F-8A9B-0C1D2E3F4A5B, name=\"root\"
+";
+    let table = gpt::table::GptTable::from_sfdisk_script(dump).unwrap();
+    assert_eq!(table.guid().to_string(), "2d3b7f4b-1d2b-4c4b-9a8a-6e1f0c6a9b11");
+    assert_eq!((table.first_usable(), table.last_usable(), table.backup_lba()), (34, 2014, 2047));
+    assert_eq!(table.partitions().keys().collect::<Vec<_>>(), vec![&1, &3]);
+    let esp = &table.partitions()[&1];
+    assert_eq!(esp.part_type_guid, gpt::partition_types::EFI);
+    assert_eq!(esp.name, "EFI \"boot\"");
+    assert_eq!((esp.first_lba, esp.last_lba), (34, 133));
+    assert_eq!(esp.flags, 1 | 1 << 2 | 1 << 60 | 1 << 63);
+    assert_eq!(table.partitions()[&3].last_lba, 2014);
+
+    // The exported script reads back into the same (renumbered) table.
+    let script = table.to_sfdisk_script();
+    assert!(script.contains("attrs=\"RequiredPartition LegacyBIOSBootable GUID:60,63\""));
+    let reread = gpt::table::GptTable::from_sfdisk_script(&script).unwrap();
+    assert_eq!(reread.partitions()[&1], table.partitions()[&1]);
+    assert_eq!(reread.partitions()[&2], table.partitions()[&3]);
+    assert_eq!(reread.primary_header().unwrap().disk_guid, *table.guid());
+
+    // Minimal scripts use defaults; bad input is rejected.
+    let table = gpt::table::GptTable::from_sfdisk_script(
+        "last-lba: 2014\nsize=100, type=U\ntype=L, name=rest\n",
+    )
+    .unwrap();
+    assert_eq!(table.partitions()[&2].first_lba, 134);
+    assert_eq!(table.partitions()[&2].last_lba, 2014);
+    gpt::table::GptTable::from_sfdisk_script("size=100\n").unwrap_err();
+    gpt::table::GptTable::from_sfdisk_script("last-lba: 2014\nstart=10, size=100\n").unwrap_err();
+    gpt::table::GptTable::from_sfdisk_script("label: dos\nlast-lba: 2014\n").unwrap_err();
+
+    // Unknown type GUIDs and unnamed attribute bits are kept.
+    let table = gpt::table::GptTable::from_sfdisk_script(
+        "last-lba: 2014\ntype=00000000-1111-2222-3333-444444444444, attrs=\"3 47 GUID:48\"\n",
+    )
+    .unwrap();
+    let part = &table.partitions()[&1];
+    assert_eq!(part.part_type_guid.guid, "00000000-1111-2222-3333-444444444444");
+    assert_eq!(part.flags, 1 << 3 | 1 << 47 | 1 << 48);
+    let script = table.to_sfdisk_script();
+    assert!(script.contains("type=00000000-1111-2222-3333-444444444444"));
+    assert!(script.contains("attrs=\"3 47 GUID:48\""));
+    let reread = gpt::table::GptTable::from_sfdisk_script(&script).unwrap();
+    assert_eq!(reread.partitions()[&1], *part);
+}
+
+#[test]
+fn test_gpttable_json() {
+    let mut table = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, 1024 * 1024, 128, None)
+        .unwrap();
+    table.add_partition("EFI \"boot\"\n", 100 * 512, gpt::partition_types::EFI, 1 | 1 << 60).unwrap();
+    table.add_partition("gap", 10 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    table.add_partition("root", 200 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    table.remove_partition(Some(2), None).unwrap();
+
+    // Exports round-trip exactly, including partition ids.
+    let json = table.to_json().unwrap();
+    assert!(json.contains("\"attrs\": \"RequiredPartition GUID:60\""));
+    assert!(json.contains("\"bytes\": 51200"));
+    assert!(json.contains("\"typename\": \"EFI\""));
+    let reread = gpt::table::GptTable::from_json(&json).unwrap();
+    assert_eq!(reread, table);
+
+    // Names outside the BMP round-trip, whether escaped or not.
+    let mut table = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, 1024 * 1024, 128, None)
+        .unwrap();
+    table.add_partition("root \u{1F600}", 100 * 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let json = table.to_json().unwrap();
+    assert_eq!(gpt::table::GptTable::from_json(&json).unwrap(), table);
+    let escaped = json.replace("\u{1F600}", "\\uD83D\\uDE00");
+    assert_ne!(escaped, json);
+    assert_eq!(gpt::table::GptTable::from_json(&escaped).unwrap(), table);
+    for lone in &["\\uD83D", "\\uD83Dx", "\\uD83D\\u0041", "\\uDE00"] {
+        let bad = json.replace("\u{1F600}", lone);
+        gpt::table::GptTable::from_json(&bad).unwrap_err();
+    }
+
+    // Output of `sfdisk --json` loads as well.
+    let sfdisk_json = r#"{
+   "partitiontable": {
+      "label": "gpt",
+      "id": "2D3B7F4B-1D2B-4C4B-9A8A-6E1F0C6A9B11",
+      "device": "/dev/loop0",
+      "unit": "sectors",
+      "firstlba": 34,
+      "lastlba": 2014,
+      "sectorsize": 512,
+      "partitions": [
+         {
+            "node": "/dev/loop0p2",
+            "start": 34,
+            "size": 100,
+            "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            "uuid": "6A1C2E34-7B59-4D1A-8E4F-0C9D3B2A1F10",
+            "name": "EFI System",
+            "attrs": "LegacyBIOSBootable"
+         }
+      ]
+   }
+}"#;
+    let table = gpt::table::GptTable::from_json(sfdisk_json).unwrap();
+    let esp = &table.partitions()[&2];
+    assert_eq!((esp.first_lba, esp.last_lba, esp.flags), (34, 133, 1 << 2));
+    assert_eq!(esp.name, "EFI System");
+    assert_eq!(table.backup_lba(), 2047);
+
+    for bad in &[
+        "",
+        "{}",
+        r#"{"partitiontable": {"label": "dos", "lastlba": 2014}}"#,
+        r#"{"partitiontable": {"lastlba": 2014, "partitions": [{"start": 1}]}}"#,
+        r#"{"partitiontable": {"lastlba": 2014, "partitions": [{"size": 10, "flags": 1}]}}"#,
+        r#"{"partitiontable": {"lastlba": "2014"}}"#,
+        r#"{"partitiontable": {"lastlba": 2014}} x"#,
+    ] {
+        gpt::table::GptTable::from_json(bad).unwrap_err();
+    }
+}
+
+#[test]
+fn test_gptdisk_json() {
+    const TOTAL_BYTES: usize = 1024 * 1024;
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; TOTAL_BYTES]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    gdisk.add_partition("rootfs", 64 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let mut image = gdisk.write().unwrap();
+
+    // The disk signature and records come from LBA0, not from the table.
+    image.get_mut()[440..444].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+    image.get_mut()[446] = 0x80;
+    image.set_position(0);
+    let mut gdisk = gpt::GptConfig::new().open_from_device(image).unwrap();
+    let json = gdisk.to_json().unwrap();
+    assert!(json.contains(&format!("\"disksize\": {},", TOTAL_BYTES)));
+    assert!(json.contains("\"signature\": \"0x12345678\""));
+    assert!(json.contains("{\"bootindicator\": 128, \"type\": \"ee\", \"start\": 1, \"size\": 2047}"));
+
+    // The table export is the device-free subset, and both read back alike.
+    let table_json = gdisk.table().unwrap().to_json().unwrap();
+    assert!(!table_json.contains("pmbr") && !table_json.contains("disksize"));
+    assert_eq!(
+        gpt::table::GptTable::from_json(&json).unwrap(),
+        gpt::table::GptTable::from_json(&table_json).unwrap()
+    );
+
+    // Without an MBR, there is no `pmbr` object.
+    let mut image = gdisk.into_inner();
+    image.get_mut()[510] = 0;
+    image.set_position(0);
+    let mut gdisk = gpt::GptConfig::new().open_from_device(image).unwrap();
+    assert!(gdisk.to_json().unwrap().contains("\"pmbr\": null,"));
+}
+
+#[test]
+fn test_gpttable_uboot_partitions() {
+    const MIB: u64 = 1024 * 1024;
+    let partitions = "uuid_disk=2d3b7f4b-1d2b-4c4b-9a8a-6e1f0c6a9b11;\
+        name=boot,start=1MiB,size=64MiB,type=system,uuid=6a1c2e34-7b59-4d1a-8e4f-0c9d3b2a1f10,bootable;\
+        name=env,size=0x20000,type=0FC63DAF-8483-4772-8E79-3D69D8477DE4;\
+        name=rootfs,size=-";
+    let table =
+        gpt::table::GptTable::from_uboot_partitions(partitions, disk::LogicalBlockSize::Lb512, 256 * MIB)
+            .unwrap();
+    assert_eq!(table.guid().to_string(), "2d3b7f4b-1d2b-4c4b-9a8a-6e1f0c6a9b11");
+    let pp = table.partitions();
+    assert_eq!((pp[&1].first_lba, pp[&1].last_lba), (2048, 2048 + 64 * 2048 - 1));
+    assert_eq!(pp[&1].part_type_guid, gpt::partition_types::EFI);
+    assert_eq!(pp[&1].flags, 1 << 2);
+    assert_eq!((pp[&2].first_lba, pp[&2].last_lba), (pp[&1].last_lba + 1, pp[&1].last_lba + 256));
+    assert_eq!((pp[&3].name.as_str(), pp[&3].last_lba), ("rootfs", table.last_usable()));
+    assert_eq!(pp[&3].part_type_guid, gpt::partition_types::LINUX_FS);
+
+    let generated = table.to_uboot_partitions().unwrap();
+    assert!(generated.starts_with(
+        "uuid_disk=2d3b7f4b-1d2b-4c4b-9a8a-6e1f0c6a9b11;name=boot,start=1MiB,size=64MiB,\
+         type=c12a7328-f81f-11d2-ba4b-00a0c93ec93b,uuid=6a1c2e34-7b59-4d1a-8e4f-0c9d3b2a1f10,bootable;\
+         name=env,start=65MiB,size=128KiB,"
+    ));
+    assert!(generated.contains("name=rootfs,start=66688KiB,size=-,"));
+
+    // The generated string describes the same layout, on any disk size.
+    let reread =
+        gpt::table::GptTable::from_uboot_partitions(&generated, disk::LogicalBlockSize::Lb512, 256 * MIB)
+            .unwrap();
+    assert_eq!(reread, table);
+    let larger =
+        gpt::table::GptTable::from_uboot_partitions(&generated, disk::LogicalBlockSize::Lb512, 512 * MIB)
+            .unwrap();
+    assert_eq!(larger.partitions()[&3].last_lba, larger.last_usable());
+
+    let mut odd = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, MIB, 128, None).unwrap();
+    odd.add_partition("a,b", 512, gpt::partition_types::LINUX_FS, 0).unwrap();
+    odd.to_uboot_partitions().unwrap_err();
+
+    for bad in &[
+        "name=a,size=-;name=b,size=1M",
+        "name=a,start=1000,size=1M",
+        "size=1M",
+        "name=a",
+        "name=a,size=0",
+        "name=a,size=1X",
+        "name=a,size=1M,type=nonsense",
+        "uuid_disk=nonsense;name=a,size=1M",
+        "name=a,start=1M,size=1M;name=b,start=1M,size=1M",
+        "name=a,size=1G",
+    ] {
+        gpt::table::GptTable::from_uboot_partitions(bad, disk::LogicalBlockSize::Lb512, 256 * MIB)
+            .unwrap_err();
+    }
+}
+
+#[test]
+fn test_gptdisk_create() {
+    let guid = uuid::Uuid::new_v4();
+    let options = gpt::CreateOptions {
+        size: Some(4 * 1024 * 1024),
+        lb_size: disk::LogicalBlockSize::Lb4096,
+        disk_guid: Some(guid),
+        entries: 64,
+        alignment: Some(1024 * 1024),
+    };
+    // The (empty) device is extended to the requested size.
+    let gdisk = gpt::GptDisk::create(std::io::Cursor::new(Vec::new()), options.clone()).unwrap();
+    let h1 = gdisk.primary_header().unwrap().clone();
+    assert_eq!(*gdisk.guid(), guid);
+    assert_eq!(h1.num_parts, 64);
+    assert_eq!(h1.first_usable, 256);
+    assert_eq!(h1.backup_lba, 1023);
+    let buf = gdisk.into_inner().into_inner();
+    assert_eq!(buf.len(), 4 * 1024 * 1024);
+    assert_eq!(&buf[510..512], &[0x55, 0xAA]);
+
+    let gdisk = gpt::GptConfig::new()
+        .logical_block_size(disk::LogicalBlockSize::Lb4096)
+        .open_from_device(std::io::Cursor::new(buf))
+        .unwrap();
+    assert_eq!(*gdisk.primary_header().unwrap(), h1);
+    assert!(gdisk.partitions().is_empty());
+
+    // Devices larger than requested, or odd sizes, are rejected.
+    let large = std::io::Cursor::new(vec![0u8; 8 * 1024 * 1024]);
+    gpt::GptDisk::create(large, options.clone()).unwrap_err();
+    let odd_size = gpt::CreateOptions { size: Some(4 * 1024 * 1024 + 512), ..options.clone() };
+    gpt::GptDisk::create(std::io::Cursor::new(Vec::new()), odd_size).unwrap_err();
+
+    // So are alignments which are not whole sectors.
+    for alignment in &[0, 512, 6144] {
+        let options = gpt::CreateOptions { alignment: Some(*alignment), ..options.clone() };
+        gpt::GptDisk::create(std::io::Cursor::new(Vec::new()), options).unwrap_err();
+    }
+}
+
+#[test]
+fn test_gptconfig_virtual_disk_size() {
+    const IMAGE_BYTES: usize = 1024 * 1024;
+    const TARGET_BYTES: u64 = 64 * 1024 * 1024;
+    let cfg = gpt::GptConfig::new()
+        .initialized(false)
+        .writable(true)
+        .virtual_disk_size(TARGET_BYTES);
+
+    // Headers target the final device, not the image.
+    let mut gdisk = cfg
+        .create_from_device(std::io::Cursor::new(vec![0u8; IMAGE_BYTES]), None)
+        .unwrap();
+    gdisk.update_partitions(BTreeMap::new()).unwrap();
+    gdisk.add_partition("rootfs", 32 * 1024 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let bak = TARGET_BYTES / 512 - 1;
+    assert_eq!(gdisk.primary_header().unwrap().backup_lba, bak);
+    assert_eq!(gdisk.primary_header().unwrap().last_usable, bak - 33);
+
+    // The backup region can be emitted as a separate blob...
+    let (offset, region) = gdisk.backup_region().unwrap();
+    assert_eq!(offset, (bak - 32) * 512);
+    assert_eq!(region.len(), 33 * 512);
+
+    // ...or the file is extended up to the target size on write.
+    let mut image = gdisk.write().unwrap();
+    assert_eq!(image.get_ref().len() as u64, TARGET_BYTES);
+    assert_eq!(&image.get_ref()[offset as usize..], &region[..]);
+    image.set_position(0);
+    let gdisk = gpt::GptConfig::new().open_from_device(image).unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+
+    // The separate blob, appended at its offset, yields the same disk.
+    let head = gdisk.into_inner().into_inner()[..IMAGE_BYTES].to_vec();
+    let mut image = vec![0u8; offset as usize];
+    image[..IMAGE_BYTES].copy_from_slice(&head);
+    image.extend_from_slice(&region);
+    let gdisk = gpt::GptConfig::new().open_from_device(std::io::Cursor::new(image)).unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+
+    // Without the backup region, the block size is detected from the primary header.
+    let mut mem_device = std::io::Cursor::new(head);
+    assert_eq!(disk::detect_logical_block_size(&mut mem_device).unwrap(), disk::LogicalBlockSize::Lb512);
+    let gdisk = gpt::GptConfig::new()
+        .logical_block_size_auto()
+        .open_from_device(mem_device)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+}
+
+#[test]
+fn test_gptdisk_primary_only() {
+    const IMAGE_BYTES: usize = 1024 * 1024;
+    let mut gdisk = gpt::GptConfig::new()
+        .initialized(false)
+        .writable(true)
+        .skip_backup(true)
+        .create_from_device(std::io::Cursor::new(vec![0u8; IMAGE_BYTES]), None)
+        .unwrap();
+    gdisk.update_partitions(BTreeMap::new()).unwrap();
+    gdisk.add_partition("rootfs", 64 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let image = gdisk.write().unwrap().into_inner();
+
+    // Only the protective MBR, primary header and array are written.
+    assert_eq!(&image[510..512], &[0x55, 0xAA]);
+    assert!(image[IMAGE_BYTES - 33 * 512..].iter().all(|b| *b == 0));
+
+    // Such images can still be opened.
+    let mut image = std::io::Cursor::new(image);
+    let gdisk = gpt::GptConfig::new().open_from_device(&mut image).unwrap();
+    assert!(gdisk.backup_header().is_none());
+    assert_eq!(gdisk.primary_header().unwrap().backup_lba, (IMAGE_BYTES / 512 - 1) as u64);
+    assert_eq!(gdisk.partitions().len(), 1);
+
+    // On the (larger) target device, the backup is completed at its end.
+    let mut target = image.into_inner();
+    target.resize(2 * IMAGE_BYTES, 0);
+    let mut gdisk = gpt::GptConfig::new()
+        .writable(true)
+        .open_from_device(std::io::Cursor::new(target))
+        .unwrap();
+    gdisk.complete_backup().unwrap();
+    let bak = (2 * IMAGE_BYTES / 512 - 1) as u64;
+    assert_eq!(gdisk.primary_header().unwrap().backup_lba, bak);
+    assert_eq!(gdisk.backup_header().unwrap().current_lba, bak);
+
+    let h2 = gdisk.backup_header().unwrap().clone();
+    let mut target = gdisk.into_inner();
+    let backup_parts =
+        gpt::partition::file_read_partitions(&mut target, &h2, disk::DEFAULT_SECTOR_SIZE).unwrap();
+    assert_eq!(backup_parts.len(), 1);
+    target.set_position(0);
+    let gdisk = gpt::GptConfig::new().open_from_device(target).unwrap();
+    assert_eq!(gdisk.backup_header().unwrap().current_lba, bak);
+    assert_eq!(gdisk.partitions().len(), 1);
+}
+
+#[test]
+fn test_gptdisk_corrupt_backup() {
+    const IMAGE_BYTES: usize = 1024 * 1024;
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; IMAGE_BYTES]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    gdisk.add_partition("rootfs", 64 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let mut image = gdisk.write().unwrap().into_inner();
+
+    // A backup header past the end of the device is missing, not corrupt.
+    let gdisk = gpt::GptConfig::new()
+        .virtual_disk_size(2 * IMAGE_BYTES as u64)
+        .open_from_device(std::io::Cursor::new(image.clone()))
+        .unwrap();
+    assert!(gdisk.backup_header().is_none());
+
+    // A backup header with a bad checksum is an error by default.
+    image[IMAGE_BYTES - 512 + 40] ^= 0xFF;
+    gpt::GptConfig::new()
+        .open_from_device(std::io::Cursor::new(image.clone()))
+        .unwrap_err();
+    let gdisk = gpt::GptConfig::new()
+        .ignore_corrupt_backup(true)
+        .open_from_device(std::io::Cursor::new(image))
+        .unwrap();
+    assert!(gdisk.backup_header().is_none());
+    assert_eq!(gdisk.partitions().len(), 1);
+}
+
+#[test]
+fn test_gptdisk_flash_images() {
+    const TARGET_BYTES: usize = 8 * 1024 * 1024;
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; 1024 * 1024]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    gdisk.add_partition("boot", 64 * 1024, gpt::partition_types::EFI, 0).unwrap();
+    gdisk.add_partition("system", 256 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+
+    let (main, backup) = gdisk.export_flash_images(TARGET_BYTES as u64).unwrap();
+    assert_eq!(main.len(), 34 * 512);
+    assert_eq!(backup.len(), 33 * 512);
+
+    // Reassemble the table from the two blobs.
+    let lb_size = disk::DEFAULT_SECTOR_SIZE;
+    let table = gpt::table::GptTable::from_flash_images(&main, &backup, lb_size).unwrap();
+    assert_eq!(table.backup_lba(), (TARGET_BYTES / 512 - 1) as u64);
+    assert_eq!(table.last_usable(), (TARGET_BYTES / 512 - 34) as u64);
+    assert_eq!(table.partitions(), gdisk.partitions());
+    assert_eq!(table.to_flash_images().unwrap(), (main.clone(), backup.clone()));
+
+    // Flashed at the start and end of the device, they form a valid disk.
+    let mut device = vec![0u8; TARGET_BYTES];
+    device[..main.len()].copy_from_slice(&main);
+    device[TARGET_BYTES - backup.len()..].copy_from_slice(&backup);
+    let flashed = gpt::GptConfig::new().open_from_device(std::io::Cursor::new(device)).unwrap();
+    assert_eq!(flashed.table().unwrap(), table);
+
+    // Mismatching or corrupted blobs are rejected.
+    let mut corrupted = backup.clone();
+    corrupted[0] ^= 0xFF;
+    gpt::table::GptTable::from_flash_images(&main, &corrupted, lb_size).unwrap_err();
+    gpt::table::GptTable::from_flash_images(&main, &backup[..512], lb_size).unwrap_err();
+    gdisk.export_flash_images(16 * 1024).unwrap_err();
+}
+
+#[test]
+fn test_gptdisk_sgdisk_backup() {
+    const TOTAL_BYTES: usize = 1024 * 1024;
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; TOTAL_BYTES]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    gdisk.add_partition("boot", 64 * 1024, gpt::partition_types::EFI, 0).unwrap();
+    gdisk.add_partition("system", 256 * 1024, gpt::partition_types::LINUX_FS, 0).unwrap();
+    gdisk.write_inplace().unwrap();
+    let mut backup = Vec::new();
+    gdisk.save_backup(&mut backup).unwrap();
+    let original = gdisk.into_inner().into_inner();
+
+    // MBR, main header, backup header and array, in 512 bytes records.
+    assert_eq!(backup.len(), 3 * 512 + 128 * 128);
+    assert_eq!(backup[..512], original[..512]);
+    assert_eq!(backup[512..1024], original[512..1024]);
+    assert_eq!(backup[1024..1536], original[TOTAL_BYTES - 512..]);
+    assert_eq!(backup[1536..], original[1024..1024 + 128 * 128]);
+
+    // Restoring to a blank device of the same size gives the same disk.
+    let restored = gpt::GptConfig::new()
+        .writable(true)
+        .restore_backup(&mut backup.as_slice(), std::io::Cursor::new(vec![0u8; TOTAL_BYTES]))
+        .unwrap();
+    assert_eq!(restored.into_inner().into_inner(), original);
+
+    // A damaged disk still produces a full backup.
+    let mut damaged = original.clone();
+    for b in &mut damaged[512..1024] {
+        *b = 0;
+    }
+    let mut gdisk = gpt::GptConfig::new().open_from_device(std::io::Cursor::new(damaged)).unwrap();
+    let mut backup2 = Vec::new();
+    gdisk.save_backup(&mut backup2).unwrap();
+    assert_eq!(backup2, backup);
+
+    // The backup header is used if the main one is corrupted, and the
+    // backup GPT is moved to the end of larger devices.
+    backup[600] ^= 0xFF;
+    let restored = gpt::GptConfig::new()
+        .writable(true)
+        .restore_backup(&mut backup.as_slice(), std::io::Cursor::new(vec![0u8; 2 * TOTAL_BYTES]))
+        .unwrap();
+    assert_eq!(restored.backup_header().unwrap().current_lba, 4095);
+    assert_eq!(restored.partitions(), gdisk.partitions());
+    let device = restored.into_inner();
+    let reopened = gpt::GptConfig::new().open_from_device(device).unwrap();
+    assert_eq!(reopened.partitions(), gdisk.partitions());
+    assert_eq!(reopened.primary_header().unwrap().backup_lba, 4095);
+
+    // Mismatching sector sizes, truncated or corrupted files are rejected.
+    let restore = |backup: &[u8], lb_size| {
+        gpt::GptConfig::new()
+            .writable(true)
+            .logical_block_size(lb_size)
+            .restore_backup(&mut &backup[..], std::io::Cursor::new(vec![0u8; TOTAL_BYTES]))
+    };
+    restore(&backup, disk::LogicalBlockSize::Lb4096).unwrap_err();
+    restore(&backup[..2000], disk::LogicalBlockSize::Lb512).unwrap_err();
+    backup[2000] ^= 0xFF;
+    restore(&backup, disk::LogicalBlockSize::Lb512).unwrap_err();
+}
+
+/// A memory device counting I/O calls.
+#[derive(Debug)]
+struct CountingDevice {
+    inner: std::io::Cursor<Vec<u8>>,
+    reads: usize,
+    writes: usize,
+}
+
+impl Read for CountingDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for CountingDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for CountingDevice {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn test_gptdisk_bulk_array_io() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let device = CountingDevice { inner: std::io::Cursor::new(image), reads: 0, writes: 0 };
+    let mut gdisk = gpt::GptConfig::new().writable(true).open_from_device(device).unwrap();
+    // Two headers, and a single read for the partition array.
+    assert_eq!(gdisk.device().reads, 3);
+
+    gdisk.remove_partition(Some(1), None).unwrap();
+    gdisk.write_inplace().unwrap();
+    // One write per partition array copy, one per header.
+    assert_eq!(gdisk.device().writes, 4);
+}
+
+#[test]
+fn test_read_partitions_large_entries() {
+    // Entries larger than 128 bytes are parsed with their own stride.
+    let lb_size = disk::DEFAULT_SECTOR_SIZE;
+    let mut table = gpt::table::GptTable::new(lb_size, 1024 * 1024, 4, None).unwrap();
+    table.add_partition("p1", 4096, gpt::partition_types::LINUX_FS, 0).unwrap();
+    table.add_partition("p2", 4096, gpt::partition_types::LINUX_FS, 0).unwrap();
+    let mut h = table.primary_header().unwrap();
+    h.part_size = 256;
+    h.crc32_parts = gpt::partition::partitions_checksum(table.partitions(), 4, 256).unwrap();
+
+    let mut image = vec![0u8; 4 * 512];
+    let array = gpt::partition::partitions_to_bytes(table.partitions(), 4, 256).unwrap();
+    image[2 * 512..2 * 512 + array.len()].copy_from_slice(&array);
+    let pp = gpt::partition::file_read_partitions(&mut std::io::Cursor::new(image), &h, lb_size)
+        .unwrap();
+    assert_eq!(&pp, table.partitions());
+}
+
+#[test]
+fn test_gptdisk_minimal_writes() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let device = CountingDevice { inner: std::io::Cursor::new(image), reads: 0, writes: 0 };
+    let mut gdisk = gpt::GptConfig::new()
+        .writable(true)
+        .minimal_writes(true)
+        .open_from_device(device)
+        .unwrap();
+
+    // A no-op edit causes no writes.
+    gdisk.write_inplace().unwrap();
+    assert_eq!(gdisk.device().writes, 0);
+    assert_eq!(gdisk.skipped_sectors(), 2 * 32 + 2);
+
+    // Renaming a partition only touches the first array sector of each copy.
+    let mut pp = gdisk.partitions().clone();
+    pp.get_mut(&1).unwrap().name = "renamed".to_string();
+    gdisk.update_partitions(pp).unwrap();
+    gdisk.write_inplace().unwrap();
+    assert_eq!(gdisk.device().writes, 4);
+    assert_eq!(gdisk.skipped_sectors(), 2 * 31);
+
+    let mut device = gdisk.into_inner().inner;
+    device.set_position(0);
+    let gdisk = gpt::GptConfig::new().open_from_device(device).unwrap();
+    assert_eq!(gdisk.partitions()[&1].name, "renamed");
+}
+
+/// Rename the single fixture partition on `device`, returning the write result.
+#[cfg(feature = "testing")]
+fn t_rename_partition(device: gpt::testing::FaultyDevice) -> (std::io::Result<()>, Vec<u8>) {
+    let mut gdisk = gpt::GptConfig::new().writable(true).open_from_device(device).unwrap();
+    let mut pp = gdisk.partitions().clone();
+    pp.get_mut(&1).unwrap().name = "renamed".to_string();
+    gdisk.update_partitions(pp).unwrap();
+    let res = gdisk.write_inplace();
+    (res, gdisk.into_inner().into_inner())
+}
+
+/// Check that an interrupted image opens with either the old or the new
+/// table, and that rewriting it restores both copies.
+#[cfg(feature = "testing")]
+fn t_check_recovery(image: Vec<u8>) {
+    let gdisk = gpt::GptConfig::new()
+        .writable(true)
+        .open_from_device(std::io::Cursor::new(image))
+        .unwrap();
+    let name = gdisk.partitions()[&1].name.clone();
+    assert!(name == "primary" || name == "renamed", "unexpected name {}", name);
+
+    let mut device = gdisk.write().unwrap();
+    device.set_position(0);
+    let gdisk = gpt::GptConfig::new().open_from_device(device).unwrap();
+    let h2 = gdisk.backup_header().unwrap().clone();
+    let pp = gdisk.partitions().clone();
+    assert_eq!(pp[&1].name, name);
+    let mut device = gdisk.into_inner();
+    let backup_parts =
+        gpt::partition::file_read_partitions(&mut device, &h2, disk::DEFAULT_SECTOR_SIZE).unwrap();
+    assert_eq!(backup_parts, pp);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_faulty_device_power_loss_boundary() {
+    // A write reaching the limit exactly is stored...
+    let mut device = gpt::testing::FaultyDevice::new(vec![0u8; 16]).lose_power_after(8);
+    device.write_all(&[1; 4]).unwrap();
+    device.write_all(&[2; 4]).unwrap();
+    assert!(device.powered_off());
+    assert_eq!(device.bytes_written(), 8);
+    // ...and the power loss shows on the next call.
+    device.write_all(&[3]).unwrap_err();
+    device.flush().unwrap_err();
+    assert_eq!(device.get_ref()[..9], [1, 1, 1, 1, 2, 2, 2, 2, 0]);
+
+    // A write crossing the limit is cut short, and fails.
+    let mut device = gpt::testing::FaultyDevice::new(vec![0u8; 16]).lose_power_after(6);
+    device.write_all(&[1; 4]).unwrap();
+    device.write_all(&[2; 4]).unwrap_err();
+    assert_eq!(device.bytes_written(), 6);
+    assert_eq!(device.get_ref()[..8], [1, 1, 1, 1, 2, 2, 0, 0]);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_gptdisk_crash_power_loss() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let (res, _) = t_rename_partition(gpt::testing::FaultyDevice::new(image.clone()));
+    res.unwrap();
+
+    // Two arrays of 32 sectors and two headers, cut at various points.
+    let total = 2 * (32 + 1) * 512;
+    for limit in (0..=total).step_by(512).chain((100..total).step_by(2048)) {
+        let device = gpt::testing::FaultyDevice::new(image.clone()).lose_power_after(limit);
+        let (res, data) = t_rename_partition(device);
+        if limit < total {
+            res.unwrap_err();
+        }
+        t_check_recovery(data);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_gptdisk_crash_failed_and_torn_writes() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    for n in 1..=4 {
+        let device = gpt::testing::FaultyDevice::new(image.clone()).fail_nth_write(n);
+        let (res, data) = t_rename_partition(device);
+        res.unwrap_err();
+        t_check_recovery(data);
+
+        // Torn writes go unnoticed, but are caught by checksums on open.
+        let device = gpt::testing::FaultyDevice::new(image.clone()).truncate_nth_write(n, 100);
+        let (res, data) = t_rename_partition(device);
+        res.unwrap();
+        t_check_recovery(data);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_gptdisk_crash_bit_flips() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let lb_size = disk::DEFAULT_SECTOR_SIZE;
+
+    // A corrupted primary header or array is recovered from the backup.
+    for lba in &[1, 2] {
+        let mut device = gpt::testing::FaultyDevice::new(image.clone());
+        device.flip_bits(*lba, lb_size, 0x10);
+        t_check_recovery(device.into_inner());
+    }
+
+    // Both copies corrupted cannot be recovered.
+    let mut device = gpt::testing::FaultyDevice::new(image.clone());
+    device.flip_bits(1, lb_size, 0x10);
+    device.flip_bits(95, lb_size, 0x10);
+    gpt::GptConfig::new().open_from_device(device).unwrap_err();
+}
@@ -0,0 +1,157 @@
+//! Hostile-input tests: parsers must return errors, never panic or
+//! over-allocate, on arbitrary (possibly crafted) disk contents.
+
+use gpt::{disk, header, mbr, partition};
+use std::io::Cursor;
+
+const ITERATIONS: usize = 2000;
+
+/// Small deterministic PRNG (xorshift64*), to keep runs reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Mostly small values, sometimes huge ones.
+    fn field(&mut self) -> u64 {
+        match self.below(4) {
+            0 => self.next(),
+            1 => u64::max_value() - self.below(4),
+            _ => self.below(256),
+        }
+    }
+}
+
+fn fixture() -> Vec<u8> {
+    std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap()
+}
+
+/// Exercise all public read paths on an image, ignoring errors.
+fn exercise(image: Vec<u8>) {
+    let _ = disk::detect_logical_block_size(&mut Cursor::new(image.clone()));
+    let _ = mbr::ProtectiveMBR::from_bytes(&image[..512], disk::LogicalBlockSize::Lb512);
+    let _ = header::Header::from_bytes(&image[512..]);
+
+    let cfg = gpt::GptConfig::new().writable(false);
+    if let Ok(mut gdisk) = cfg.open_from_device(Box::new(Cursor::new(image.clone()))) {
+        let lb_size = *gdisk.logical_block_size();
+        for part in gdisk.partitions().values() {
+            let _ = part.bytes_start(lb_size);
+            let _ = part.bytes_len(lb_size);
+            let _ = part.size();
+        }
+        let _ = gdisk.find_free_sectors();
+        let _ = gdisk.find_next_partition_id();
+        let _ = gdisk.add_partition("fuzz", 4096, gpt::partition_types::LINUX_FS, 0);
+    }
+    let cfg = gpt::GptConfig::new().logical_block_size_auto();
+    let _ = cfg.open_from_device(Box::new(Cursor::new(image)));
+}
+
+#[test]
+fn test_hostile_random_bytes() {
+    let base = fixture();
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..ITERATIONS {
+        let mut image = base.clone();
+        // Corrupt a few bytes in the metadata areas (MBR, headers, arrays).
+        let flips = 1 + rng.below(16);
+        for _ in 0..flips {
+            let offset = if rng.below(2) == 0 {
+                rng.below(34 * 512)
+            } else {
+                image.len() as u64 - 1 - rng.below(33 * 512)
+            };
+            image[offset as usize] = rng.next() as u8;
+        }
+        exercise(image);
+    }
+}
+
+#[test]
+fn test_hostile_crafted_headers() {
+    let base = fixture();
+    let good = header::Header::from_bytes(&base[512..1024]).unwrap();
+    let mut rng = Rng(0xDEAD_BEEF_CAFE_F00D);
+    for _ in 0..ITERATIONS {
+        let mut image = base.clone();
+        // Craft a header with a valid CRC, so that its fields are trusted.
+        let mut h = good.clone();
+        match rng.below(6) {
+            0 => h.num_parts = rng.field() as u32,
+            1 => h.part_size = rng.field() as u32,
+            2 => h.part_start = rng.field(),
+            3 => h.first_usable = rng.field(),
+            4 => h.last_usable = rng.field(),
+            _ => h.header_size_le = rng.field() as u32,
+        }
+        // Also craft a partition entry, with a matching array CRC.
+        let entry = 2 * 512 + (rng.below(4) as usize) * 128;
+        let lba_field = entry + 32 + 8 * rng.below(2) as usize;
+        image[lba_field..lba_field + 8].copy_from_slice(&rng.field().to_le_bytes());
+        let array_end = 2 * 512 + 128 * 128;
+        h.crc32_parts = crc::crc32::checksum_ieee(&image[2 * 512..array_end]);
+
+        let bytes = h.to_bytes().unwrap();
+        image[512..512 + bytes.len()].copy_from_slice(&bytes);
+        exercise(image);
+    }
+}
+
+#[test]
+fn test_hostile_oversized_array() {
+    let mut image = fixture();
+    let mut h = header::Header::from_bytes(&image[512..1024]).unwrap();
+    h.num_parts = u32::max_value();
+    let bytes = h.to_bytes().unwrap();
+    image[512..512 + bytes.len()].copy_from_slice(&bytes);
+
+    let err = partition::file_read_partitions(&mut Cursor::new(&image), &h, disk::DEFAULT_SECTOR_SIZE)
+        .unwrap_err();
+    assert!(err.to_string().contains("too large"));
+    // Opening falls back to the (sane) backup copy...
+    let cfg = gpt::GptConfig::new();
+    let gdisk = cfg.open_from_device(Box::new(Cursor::new(image.clone()))).unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().num_parts, 128);
+    // ...and fails without it.
+    let mut no_backup = image.clone();
+    let len = no_backup.len();
+    no_backup[len - 512..].copy_from_slice(&[0u8; 512]);
+    let cfg = gpt::GptConfig::new();
+    cfg.open_from_device(Box::new(Cursor::new(no_backup))).unwrap_err();
+
+    // Writing a header checks it before reading back its array.
+    let err = h
+        .write_primary(&mut Cursor::new(image.clone()), disk::DEFAULT_SECTOR_SIZE)
+        .unwrap_err();
+    assert!(err.to_string().contains("too large"));
+
+    // Larger arrays are accepted up to the given limit.
+    h.num_parts = 1024;
+    partition::file_read_partitions(&mut Cursor::new(&image), &h, disk::DEFAULT_SECTOR_SIZE)
+        .unwrap_err();
+    h.validate(disk::DEFAULT_SECTOR_SIZE, 1024 * 128).unwrap();
+    h.validate(disk::DEFAULT_SECTOR_SIZE, 1024 * 128 - 1).unwrap_err();
+}
+
+#[test]
+fn test_hostile_partition_arrays() {
+    let mut rng = Rng(0x0123_4567_89AB_CDEF);
+    for _ in 0..ITERATIONS {
+        let len = rng.below(4096) as usize;
+        let buf: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+        let _ = partition::partitions_from_bytes(&buf, rng.field() as u32, rng.field() as u32);
+        let _ = partition::Partition::from_bytes(&buf);
+        let _ = header::Header::from_bytes(&buf);
+        let _ = mbr::PartRecord::from_bytes(&buf);
+    }
+}
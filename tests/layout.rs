@@ -0,0 +1,205 @@
+use gpt::disk;
+use gpt::layout::{GuidPolicy, Layout, LayoutEntry, Operation, Size};
+use gpt::partition_types;
+use gpt::table::GptTable;
+
+const MIB: u64 = 1024 * 1024;
+
+fn existing_table() -> GptTable {
+    let mut table = GptTable::new(disk::LogicalBlockSize::Lb512, 64 * MIB, 128, None).unwrap();
+    table
+        .add_partition("boot", 8 * MIB, partition_types::LINUX_FS, 0)
+        .unwrap();
+    table
+        .add_partition("swap", 4 * MIB, partition_types::LINUX_SWAP, 0)
+        .unwrap();
+    table
+        .add_partition("junk", MIB, partition_types::BASIC, 0)
+        .unwrap();
+    table
+}
+
+#[test]
+fn test_layout_plan() {
+    let table = existing_table();
+    let boot = table.partitions()[&1].clone();
+    let swap = table.partitions()[&2].clone();
+    let mut esp = LayoutEntry::new("boot", partition_types::EFI, Size::Bytes(8 * MIB));
+    esp.flags = 1 << 2;
+    let mut layout = Layout {
+        entries: vec![
+            esp,
+            LayoutEntry::new(
+                "swap-main",
+                partition_types::LINUX_SWAP,
+                Size::Bytes(6 * MIB),
+            ),
+            LayoutEntry::new("data", partition_types::LINUX_FS, Size::Remaining),
+        ],
+        allow_delete: false,
+    };
+
+    // Partitions not in the layout are only deleted when allowed.
+    layout.plan(&table).unwrap_err();
+    layout.allow_delete = true;
+    let plan = layout.plan(&table).unwrap();
+    let data_first = swap.first_lba + 6 * MIB / 512;
+    assert_eq!(
+        plan.operations()[..5],
+        [
+            Operation::Delete {
+                id: 3,
+                name: "junk".to_string()
+            },
+            Operation::Retype {
+                id: 1,
+                from: partition_types::LINUX_FS,
+                to: partition_types::EFI
+            },
+            Operation::SetAttributes {
+                id: 1,
+                from: 0,
+                to: 1 << 2
+            },
+            Operation::Rename {
+                id: 2,
+                from: "swap".to_string(),
+                to: "swap-main".to_string()
+            },
+            Operation::Resize {
+                id: 2,
+                from: swap.last_lba,
+                to: data_first - 1
+            },
+        ]
+    );
+    match &plan.operations()[5] {
+        Operation::Add { id, partition } => {
+            assert_eq!(*id, 3);
+            assert_eq!(
+                (partition.first_lba, partition.last_lba),
+                (data_first, table.last_usable())
+            );
+        }
+        op => panic!("unexpected operation {}", op),
+    }
+    assert_eq!(plan.operations().len(), 6);
+    assert!(plan
+        .to_string()
+        .starts_with("delete partition 3 (\"junk\")\nretype partition 1: "));
+
+    // Matched partitions keep their GUID and start.
+    let target = plan.target().partitions();
+    assert_eq!(target[&1].part_guid, boot.part_guid);
+    assert_eq!(
+        (target[&1].first_lba, target[&1].last_lba),
+        (boot.first_lba, boot.last_lba)
+    );
+    assert_eq!(target[&2].part_guid, swap.part_guid);
+    assert_eq!(plan.base(), &table);
+
+    // The target already matches the layout.
+    assert!(layout.plan(plan.target()).unwrap().is_empty());
+}
+
+#[test]
+fn test_layout_guid_policy_and_errors() {
+    let table = existing_table();
+    let swap_guid = table.partitions()[&2].part_guid;
+    let new_guid = uuid::Uuid::new_v4();
+    let mut renamed = LayoutEntry::new("other", partition_types::LINUX_FS, Size::Bytes(4 * MIB));
+    renamed.guid = GuidPolicy::Fixed(swap_guid);
+    let mut fresh = LayoutEntry::new("swap", partition_types::LINUX_SWAP, Size::Bytes(MIB));
+    fresh.guid = GuidPolicy::Fixed(new_guid);
+    let layout = Layout {
+        entries: vec![
+            LayoutEntry::new("boot", partition_types::LINUX_FS, Size::Bytes(8 * MIB)),
+            renamed,
+            fresh,
+        ],
+        allow_delete: true,
+    };
+    let plan = layout.plan(&table).unwrap();
+    let target = plan.target().partitions();
+    // A fixed GUID matches regardless of name, or is given to a new partition.
+    assert_eq!(
+        (target[&2].name.as_str(), target[&2].part_guid),
+        ("other", swap_guid)
+    );
+    assert_eq!(target[&3].name, "swap");
+    assert_eq!(target[&3].part_guid, new_guid);
+    assert_eq!(target[&3].first_lba, table.partitions()[&3].first_lba);
+
+    // Partitions are never moved to make room.
+    let layout = Layout {
+        entries: vec![
+            LayoutEntry::new("boot", partition_types::LINUX_FS, Size::Bytes(9 * MIB)),
+            LayoutEntry::new("swap", partition_types::LINUX_SWAP, Size::Bytes(4 * MIB)),
+            LayoutEntry::new("junk", partition_types::BASIC, Size::Bytes(MIB)),
+        ],
+        allow_delete: false,
+    };
+    layout.plan(&table).unwrap_err();
+
+    for entries in &[
+        vec![
+            LayoutEntry::new("a", partition_types::LINUX_FS, Size::Remaining),
+            LayoutEntry::new("b", partition_types::LINUX_FS, Size::Remaining),
+        ],
+        vec![
+            LayoutEntry::new("a", partition_types::LINUX_FS, Size::Bytes(MIB)),
+            LayoutEntry::new("a", partition_types::LINUX_FS, Size::Bytes(MIB)),
+        ],
+        vec![LayoutEntry::new(
+            "a",
+            partition_types::LINUX_FS,
+            Size::Bytes(0),
+        )],
+        vec![LayoutEntry::new(
+            "a",
+            partition_types::LINUX_FS,
+            Size::Bytes(128 * MIB),
+        )],
+    ] {
+        let layout = Layout {
+            entries: entries.clone(),
+            allow_delete: true,
+        };
+        layout.plan(&table).unwrap_err();
+    }
+}
+
+#[test]
+fn test_gptdisk_reconcile() {
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; 32 * MIB as usize]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    let layout = Layout {
+        entries: vec![
+            LayoutEntry::new("esp", partition_types::EFI, Size::Bytes(4 * MIB)),
+            LayoutEntry::new("root", partition_types::LINUX_FS, Size::Remaining),
+        ],
+        allow_delete: false,
+    };
+    let plan = gdisk.reconcile(&layout).unwrap();
+    assert_eq!(plan.operations().len(), 2);
+
+    // Plans are only applied to the table they were computed from.
+    let id = gdisk
+        .add_partition("other", MIB, partition_types::LINUX_FS, 0)
+        .unwrap();
+    gdisk.apply_plan(&plan).unwrap_err();
+    gdisk.remove_partition(Some(id), None).unwrap();
+
+    gdisk.apply_plan(&plan).unwrap();
+    let device = gdisk.write().unwrap();
+    let gdisk = gpt::GptConfig::new().open_from_device(device).unwrap();
+    let table = gdisk.table().unwrap();
+    assert_eq!(table.partitions()[&1].name, "esp");
+    assert_eq!(table.partitions()[&2].last_lba, table.last_usable());
+    let plan = gdisk.reconcile(&layout).unwrap();
+    assert!(plan.is_empty());
+    assert_eq!(plan.to_string(), "no changes\n");
+}
@@ -104,3 +104,14 @@ fn test_mbr_disksig() {
     let s2 = mbr::read_disk_signature(&mut tempdisk).unwrap();
     assert_eq!(s1.to_vec(), s2.to_vec());
 }
+
+#[test]
+fn test_mbr_large_sectors() {
+    let m0 = mbr::ProtectiveMBR::with_lb_size(0x10);
+    let mut sector = m0.as_bytes().unwrap();
+    sector.resize(2048, 0x00);
+
+    let m1 = mbr::ProtectiveMBR::from_bytes(&sector, disk::LogicalBlockSize::Lb2048).unwrap();
+    assert_eq!(m1.partition(0).unwrap().lb_size, 0x10);
+    mbr::ProtectiveMBR::from_bytes(&sector, disk::LogicalBlockSize::Lb4096).unwrap_err();
+}
@@ -0,0 +1,164 @@
+use gpt::disk;
+use gpt::partition_types;
+use gpt::table::qualcomm::PartitionXml;
+
+const PARTITION_XML: &str = r#"<?xml version="1.0"?>
+<configuration>
+  <parser_instructions>
+    <!-- Sizes are in KiB -->
+    WRITE_PROTECT_BOUNDARY_IN_KB = 0
+    SECTOR_SIZE_IN_BYTES = 4096
+    GROW_LAST_PARTITION_TO_FILL_DISK = true
+  </parser_instructions>
+  <physical_partition>
+    <partition label="xbl_a" size_in_kb="3584" type="DEA0BA2C-CBDD-4805-B4F9-F428251C3E98" bootable="true" readonly="true" filename="xbl.elf"/>
+    <partition label="xbl_config_a" size_in_kb="128" type="5A325AE4-4276-B66D-0ADD-3494DF27706A" readonly="true" filename="xbl_config.elf"/>
+    <partition label="userdata" size_in_kb="0" type="{1B81E7E6-F50D-419B-A739-2AEEF8DA3335}" filename="userdata.img" sparse="true"/>
+  </physical_partition>
+  <physical_partition>
+    <partition label="modem_a" size_in_kb="0.5" type="EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" uniqueguid="6A1C2E34-7B59-4D1A-8E4F-0C9D3B2A1F10" filename=""/>
+  </physical_partition>
+</configuration>
+"#;
+
+/// Return the value of attribute `name` in an XML element.
+fn attr<'a>(element: &'a str, name: &str) -> &'a str {
+    let start = element.find(&format!(" {}=\"", name)).unwrap() + name.len() + 3;
+    &element[start..start + element[start..].find('"').unwrap()]
+}
+
+/// Apply the patches for `target` to `buf`, like a flashing tool would.
+fn apply_patches(patch_xml: &str, target: &str, buf: &mut [u8], num_disk_sectors: u64, lb: u64) {
+    let eval = |expr: &str| -> u64 {
+        if expr.starts_with("NUM_DISK_SECTORS-") {
+            num_disk_sectors - expr[17..].trim_end_matches('.').parse::<u64>().unwrap()
+        } else {
+            expr.parse().unwrap()
+        }
+    };
+    for element in patch_xml.split("<patch ").skip(1) {
+        if attr(element, "filename") != target {
+            continue;
+        }
+        let offset = (eval(attr(element, "start_sector")) * lb + eval(attr(element, "byte_offset"))) as usize;
+        let size = eval(attr(element, "size_in_bytes")) as usize;
+        let value = attr(element, "value");
+        let value = if value.starts_with("CRC32(") {
+            let args: Vec<&str> = value[6..value.len() - 1].split(',').collect();
+            let start = (eval(args[0]) * lb) as usize;
+            u64::from(crc::crc32::checksum_ieee(&buf[start..start + eval(args[1]) as usize]))
+        } else {
+            eval(value)
+        };
+        buf[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+}
+
+#[test]
+fn test_qualcomm_partition_xml() {
+    let layout = PartitionXml::parse(PARTITION_XML).unwrap();
+    assert_eq!((layout.sector_size, layout.grow_last), (4096, true));
+    assert_eq!(layout.luns.len(), 2);
+    let xbl = &layout.luns[0][0];
+    assert_eq!((xbl.label.as_str(), xbl.size), ("xbl_a", 3584 * 1024));
+    assert_eq!(xbl.part_type, partition_types::ANDROID_XBL_A_MISC);
+    assert_eq!(xbl.flags, 1 << 60 | 1 << 2);
+    assert!(layout.luns[0][2].sparse);
+    assert_eq!(layout.luns[1][0].size, 512);
+    assert!(layout.luns[1][0].uuid.is_some());
+    assert_eq!(PartitionXml::parse(&layout.to_xml()).unwrap(), layout);
+
+    PartitionXml::parse("<configuration><partition label=\"x\" type=\"00\"/></configuration>").unwrap_err();
+    PartitionXml::parse("<configuration><physical_partition><partition label=\"x\"/>").unwrap_err();
+}
+
+#[test]
+fn test_qualcomm_generate() {
+    let layout = PartitionXml::parse(PARTITION_XML).unwrap();
+    let lb_size = disk::LogicalBlockSize::Lb4096;
+    let images = layout.generate(0).unwrap();
+    let pp = images.table.partitions();
+    assert_eq!((pp[&1].first_lba, pp[&1].last_lba), (6, 6 + 896 - 1));
+    assert_eq!((pp[&2].first_lba, pp[&2].last_lba), (902, 902 + 32 - 1));
+    assert_eq!(pp[&3].name, "userdata");
+    assert_eq!(images.gpt_main.len(), 6 * 4096);
+    assert_eq!(images.gpt_backup.len(), 5 * 4096);
+    assert!(images.rawprogram.contains(
+        "filename=\"xbl.elf\" label=\"xbl_a\" num_partition_sectors=\"896\" partofsingleimage=\"false\" \
+         physical_partition_number=\"0\" readbackverify=\"false\" size_in_KB=\"3584.0\" sparse=\"false\" \
+         start_byte_hex=\"0x6000\" start_sector=\"6\""
+    ));
+    assert!(images.rawprogram.contains("label=\"userdata\" num_partition_sectors=\"0\""));
+    assert!(images.rawprogram.contains("start_byte_hex=\"(4096*NUM_DISK_SECTORS)-20480.\" start_sector=\"NUM_DISK_SECTORS-5.\""));
+
+    // Flash and patch a 64 MiB disk, then check it holds a valid GPT.
+    let num_disk_sectors = 16 * 1024;
+    let mut device = vec![0u8; num_disk_sectors * 4096];
+    device[..images.gpt_main.len()].copy_from_slice(&images.gpt_main);
+    let backup_start = device.len() - images.gpt_backup.len();
+    device[backup_start..].copy_from_slice(&images.gpt_backup);
+    apply_patches(&images.patch, "DISK", &mut device, num_disk_sectors as u64, 4096);
+
+    // Patching the image files first gives the same result.
+    let mut main = images.gpt_main.clone();
+    let mut backup = images.gpt_backup.clone();
+    apply_patches(&images.patch, "gpt_main0.bin", &mut main, num_disk_sectors as u64, 4096);
+    apply_patches(&images.patch, "gpt_backup0.bin", &mut backup, num_disk_sectors as u64, 4096);
+    assert_eq!(main[..], device[..main.len()]);
+    assert_eq!(backup[..], device[backup_start..]);
+
+    let gdisk = gpt::GptConfig::new()
+        .logical_block_size(lb_size)
+        .open_from_device(std::io::Cursor::new(device))
+        .unwrap();
+    let table = gdisk.table().unwrap();
+    assert_eq!(table.backup_lba(), num_disk_sectors as u64 - 1);
+    assert_eq!(table.partitions()[&3].last_lba, table.last_usable());
+    assert_eq!(table.partitions()[&1], pp[&1]);
+    assert_eq!(gdisk.backup_header().unwrap().current_lba, num_disk_sectors as u64 - 1);
+
+    // Describe the flashed GPT back as partition.xml.
+    let described = PartitionXml::from_tables(&[table]);
+    assert!(described.grow_last);
+    assert_eq!(described.luns[0].len(), 3);
+    assert_eq!(described.luns[0][0].size, layout.luns[0][0].size);
+    assert_eq!(described.luns[0][2].size, 0);
+    let reparsed = PartitionXml::parse(&described.to_xml()).unwrap();
+    assert_eq!(reparsed, described);
+
+    layout.generate(2).unwrap_err();
+}
+
+#[test]
+fn test_qualcomm_generate_per_lun() {
+    let mut layout = PartitionXml::parse(PARTITION_XML).unwrap();
+    layout.sector_size = 512;
+    let lun0 = layout.generate(0).unwrap();
+    assert_eq!(lun0.table.partitions()[&1].first_lba, 34);
+    assert!(lun0.patch.contains("Update last partition with actual size"));
+
+    // Only a last partition without size grows, the others keep theirs.
+    let lun1 = layout.generate(1).unwrap();
+    let modem = &lun1.table.partitions()[&1];
+    assert_eq!((modem.first_lba, modem.last_lba), (34, 34));
+    assert!(lun1.rawprogram.contains("label=\"modem_a\" num_partition_sectors=\"1\""));
+    assert!(!lun1.patch.contains("Update last partition with actual size"));
+    let mut other = gpt::table::GptTable::new(disk::LogicalBlockSize::Lb512, 1024 * 1024, 128, None).unwrap();
+    other.add_partition("modem_a", 512, partition_types::BASIC, 0).unwrap();
+    let described = PartitionXml::from_tables(&[lun0.table, other]);
+    assert!(described.grow_last);
+    assert_eq!(described.luns[0][2].size, 0);
+    assert_eq!(described.luns[1][0].size, 512);
+    assert!(!described.generate(1).unwrap().patch.contains("Update last partition"));
+
+    // Sector sizes are checked, and sizes can't overflow the disk.
+    layout.sector_size = 1000;
+    layout.generate(0).unwrap_err();
+    layout.sector_size = 4096;
+    layout.luns[1][0].size = u64::max_value();
+    layout.generate(1).unwrap_err();
+    layout.luns[1][0].size = u64::max_value() / 2;
+    let modem = layout.luns[1][0].clone();
+    layout.luns[1].push(modem);
+    layout.generate(1).unwrap_err();
+}
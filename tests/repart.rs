@@ -0,0 +1,170 @@
+use gpt::disk;
+use gpt::partition_types;
+use gpt::table::repart::{self, Definition};
+use gpt::table::GptTable;
+
+const MIB: u64 = 1024 * 1024;
+
+fn def(name: &str, content: &str) -> Definition {
+    Definition::parse(name, content).unwrap()
+}
+
+fn part_len(table: &GptTable, id: u32) -> u64 {
+    let p = &table.partitions()[&id];
+    p.last_lba - p.first_lba + 1
+}
+
+#[test]
+fn test_repart_parse_definition() {
+    let d = def(
+        "10-root.conf",
+        "# Root filesystem
+[Partition]
+Type=root-x86-64
+Label=system
+UUID=4f68bce3-e8cd-4db1-96e7-fbcaf984b709
+SizeMinBytes=1.5G
+SizeMaxBytes=4G
+Weight=500
+Priority=-10
+PaddingMinBytes=1M
+Flags=0x1000000000000001
+Format=ext4
+
+[Other]
+Type=swap
+",
+    );
+    assert_eq!(d.name, "10-root.conf");
+    assert_eq!(d.part_type, partition_types::LINUX_ROOT_X64);
+    assert_eq!(d.label, "system");
+    assert_eq!(d.uuid.unwrap().to_string(), "4f68bce3-e8cd-4db1-96e7-fbcaf984b709");
+    assert_eq!(d.size_min_bytes, Some(3 * 512 * MIB));
+    assert_eq!(d.size_max_bytes, Some(4096 * MIB));
+    assert_eq!((d.weight, d.priority), (500, -10));
+    assert_eq!(d.padding_min_bytes, MIB);
+    assert_eq!(d.flags, 1 << 60 | 1);
+
+    // Defaults, with the label taken from the type.
+    let d = def("20-esp.conf", "[Partition]\nType=C12A7328-F81F-11D2-BA4B-00A0C93EC93B\n");
+    assert_eq!(d.part_type, partition_types::EFI);
+    assert_eq!(d.label, "esp");
+    assert_eq!((d.weight, d.priority, d.flags), (repart::DEFAULT_WEIGHT, 0, 0));
+    assert_eq!((d.size_min_bytes, d.size_max_bytes), (None, None));
+
+    for bad in &[
+        "[Partition]\nLabel=x\n",
+        "[Partition]\nType=unknown\n",
+        "[Partition]\nType=root\n",
+        "[Partition]\nType=swap\nWeight=-1\n",
+        "[Partition]\nType=swap\nPriority=1001\n",
+        "[Partition]\nType=swap\nSizeMinBytes=10X\n",
+        "[Partition]\nType=swap\nSizeMinBytes=2G\nSizeMaxBytes=1G\n",
+        "[Partition]\nType\n",
+    ] {
+        Definition::parse("bad.conf", bad).unwrap_err();
+    }
+}
+
+#[test]
+fn test_repart_weights() {
+    let mut table = GptTable::new(disk::LogicalBlockSize::Lb512, 64 * MIB, 128, None).unwrap();
+    let defs = vec![
+        def("10-esp.conf", "[Partition]\nType=esp\nSizeMinBytes=16M\nSizeMaxBytes=16M\n"),
+        def("20-root.conf", "[Partition]\nType=linux-generic\nSizeMinBytes=8M\n"),
+        def("30-home.conf", "[Partition]\nType=linux-generic\nSizeMinBytes=4M\nWeight=3000\n"),
+    ];
+    let ids = table.apply_repart(&defs).unwrap();
+    assert_eq!(ids, vec![Some(1), Some(2), Some(3)]);
+
+    let pp = table.partitions();
+    assert_eq!(pp[&1].first_lba, 40);
+    assert_eq!(part_len(&table, 1), 16 * MIB / 512);
+    assert_eq!(pp[&2].first_lba, pp[&1].last_lba + 1);
+    assert_eq!(pp[&3].first_lba, pp[&2].last_lba + 1);
+    // Remaining space is shared 1:3, up to the last aligned LBA.
+    assert_eq!(pp[&3].last_lba, 131031);
+    assert_eq!(part_len(&table, 2), 24552);
+    assert_eq!(part_len(&table, 3), 73672);
+    assert_eq!((pp[&2].name.as_str(), pp[&3].name.as_str()), ("linux-generic", "linux-generic-2"));
+    assert_eq!(pp[&1].part_type_guid, partition_types::EFI);
+}
+
+#[test]
+fn test_repart_existing_and_padding() {
+    let mut table = GptTable::new(disk::LogicalBlockSize::Lb512, 64 * MIB, 128, None).unwrap();
+    let root = table.add_partition("root", 10 * MIB, partition_types::LINUX_ROOT_X64, 0).unwrap();
+    let foreign = table.add_partition("data", MIB, partition_types::LINUX_FS, 0).unwrap();
+    table.remove_partition(Some(foreign), None).unwrap();
+    let before = table.partitions()[&root].clone();
+
+    let defs = vec![
+        def("10-root.conf", "[Partition]\nType=root-x86-64\nSizeMaxBytes=20M\n"),
+        def("20-swap.conf", "[Partition]\nType=swap\nSizeMinBytes=8M\nWeight=0\nPaddingMinBytes=1M\n"),
+        def("30-srv.conf", "[Partition]\nType=srv\nSizeMinBytes=1M\nSizeMaxBytes=2M\nFlags=4\n"),
+    ];
+    let ids = table.apply_repart(&defs).unwrap();
+    assert_eq!(ids, vec![Some(root), Some(2), Some(3)]);
+
+    // The existing partition keeps its identity and grows up to its maximum.
+    let pp = table.partitions();
+    assert_eq!(pp[&root].part_guid, before.part_guid);
+    assert_eq!(pp[&root].first_lba, before.first_lba);
+    // It starts unaligned, so it ends on the last aligned LBA within its maximum.
+    assert_eq!(part_len(&table, root), 20 * MIB / 512 - 2);
+    assert_eq!((pp[&root].last_lba + 1) % 8, 0);
+    assert_eq!(pp[&2].first_lba, pp[&root].last_lba + 1);
+    assert_eq!(part_len(&table, 2), 8 * MIB / 512);
+    assert_eq!(pp[&3].first_lba, pp[&2].last_lba + 1 + MIB / 512);
+    assert_eq!(part_len(&table, 3), 2 * MIB / 512);
+    assert_eq!(pp[&3].flags, 4);
+
+    // Applying again is a no-op.
+    let snapshot = table.clone();
+    table.apply_repart(&defs).unwrap();
+    assert_eq!(table, snapshot);
+}
+
+#[test]
+fn test_repart_priority() {
+    let mut table = GptTable::new(disk::LogicalBlockSize::Lb512, 16 * MIB, 128, None).unwrap();
+    let mut defs = vec![
+        def("10-a.conf", "[Partition]\nType=linux-generic\nSizeMinBytes=8M\n"),
+        def("20-b.conf", "[Partition]\nType=swap\nSizeMinBytes=8M\nPriority=10\n"),
+    ];
+    let ids = table.apply_repart(&defs).unwrap();
+    assert_eq!(ids, vec![Some(1), None]);
+    assert_eq!(table.partitions().len(), 1);
+
+    // Partitions with non-positive priorities are never dropped.
+    let mut table = GptTable::new(disk::LogicalBlockSize::Lb512, 16 * MIB, 128, None).unwrap();
+    defs[1].priority = 0;
+    table.apply_repart(&defs).unwrap_err();
+    assert!(table.partitions().is_empty());
+}
+
+#[test]
+fn test_repart_directory_to_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("20-root.conf"), "[Partition]\nType=linux-generic\nLabel=root\n").unwrap();
+    std::fs::write(dir.path().join("10-esp.conf"), "[Partition]\nType=esp\nSizeMaxBytes=12M\n").unwrap();
+    std::fs::write(dir.path().join("README"), "not a definition").unwrap();
+    let defs = repart::read_definitions(dir.path()).unwrap();
+    let names: Vec<&str> = defs.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["10-esp.conf", "20-root.conf"]);
+
+    let mut gdisk = gpt::GptDisk::create(
+        std::io::Cursor::new(vec![0u8; 32 * MIB as usize]),
+        gpt::CreateOptions::default(),
+    )
+    .unwrap();
+    let mut table = gdisk.table().unwrap();
+    table.apply_repart(&defs).unwrap();
+    gdisk.update_table(table).unwrap();
+    let device = gdisk.write().unwrap();
+    let gdisk = gpt::GptConfig::new().open_from_device(device).unwrap();
+    let table = gdisk.table().unwrap();
+    assert_eq!(table.partitions()[&1].name, "esp");
+    assert_eq!(part_len(&table, 1), 12 * MIB / 512);
+    assert_eq!(table.partitions()[&2].name, "root");
+}
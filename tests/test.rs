@@ -91,4 +91,76 @@ fn test_partition_type_fromstr() {
     assert_eq!(gpt::partition_types::Type::from_str("933AC7E1-2EB4-4F13-B844-0E14E2AEF915").unwrap(), gpt::partition_types::LINUX_HOME);
     assert_eq!(gpt::partition_types::Type::from_str("114EAFFE-1552-4022-B26E-9B053604CF84").unwrap(), gpt::partition_types::ANDROID_BOOTLOADER2);
     assert_eq!(gpt::partition_types::Type::from_str("00000000-0000-0000-0000-000000000000").unwrap(), gpt::partition_types::UNUSED);
-}
\ No newline at end of file
+}
+#[test]
+fn test_header_partition_bytes_roundtrip() {
+    let image = std::fs::read("tests/fixtures/gpt-linux-disk-01.img").unwrap();
+    let lb_size = 512;
+
+    // Primary header at LBA1.
+    let h = Header::from_bytes(&image[lb_size..2 * lb_size]).unwrap();
+    assert_eq!(h.current_lba, 1);
+    assert_eq!(h.to_bytes().unwrap(), image[lb_size..lb_size + 92].to_vec());
+    assert_eq!(h.checksum().unwrap(), h.crc32);
+
+    // Partition array at LBA2, parsed and re-serialized from memory.
+    let array_start = h.part_start as usize * lb_size;
+    let array_len = (h.num_parts * h.part_size) as usize;
+    let array = &image[array_start..array_start + array_len];
+    let pp = gpt::partition::partitions_from_bytes(array, h.num_parts, h.part_size).unwrap();
+    assert_eq!(pp.len(), 1);
+    assert_eq!(pp[&1].name, "primary");
+    assert_eq!(
+        gpt::partition::partitions_to_bytes(&pp, h.num_parts, h.part_size).unwrap(),
+        array.to_vec()
+    );
+    assert_eq!(
+        gpt::partition::partitions_checksum(&pp, h.num_parts, h.part_size).unwrap(),
+        h.crc32_parts
+    );
+
+    // Sparse table: slot 1 empty, slot 2 used. Entries keep their index.
+    let mut sparse = std::collections::BTreeMap::new();
+    sparse.insert(2, pp[&1].clone());
+    let bytes = gpt::partition::partitions_to_bytes(&sparse, h.num_parts, h.part_size).unwrap();
+    assert_eq!(bytes.len(), array_len);
+    assert!(bytes[..128].iter().all(|b| *b == 0));
+    assert_eq!(bytes[128..256].to_vec(), array[..128].to_vec());
+    assert!(bytes[256..].iter().all(|b| *b == 0));
+    assert_eq!(
+        gpt::partition::partitions_from_bytes(&bytes, h.num_parts, h.part_size).unwrap(),
+        sparse
+    );
+    assert_eq!(
+        gpt::partition::partitions_checksum(&sparse, h.num_parts, h.part_size).unwrap(),
+        crc::crc32::checksum_ieee(&bytes)
+    );
+    assert_ne!(
+        gpt::partition::partitions_checksum(&sparse, h.num_parts, h.part_size).unwrap(),
+        h.crc32_parts
+    );
+    sparse.insert(h.num_parts + 1, pp[&1].clone());
+    gpt::partition::partitions_to_bytes(&sparse, h.num_parts, h.part_size).unwrap_err();
+
+    let p = Partition::from_bytes(&array[..128]).unwrap();
+    assert_eq!(p, pp[&1]);
+    assert_eq!(p.to_bytes(128).unwrap(), array[..128].to_vec());
+    p.to_bytes(64).unwrap_err();
+    Partition::from_bytes(&array[..64]).unwrap_err();
+
+    // Unknown type GUIDs are kept, and the entry stays in use.
+    let mut entry = array[..128].to_vec();
+    entry[..16].copy_from_slice(&[0x11; 16]);
+    let p = Partition::from_bytes(&entry).unwrap();
+    assert_eq!(p.part_type_guid.guid, "11111111-1111-1111-1111-111111111111");
+    assert!(p.is_used());
+    assert_eq!(p.to_bytes(128).unwrap(), entry);
+
+    // Corrupted header CRC and signature.
+    let mut bad = image[lb_size..2 * lb_size].to_vec();
+    bad[40] ^= 0xFF;
+    Header::from_bytes(&bad).unwrap_err();
+    bad[0] = b'X';
+    Header::from_bytes(&bad).unwrap_err();
+    Header::from_bytes(&image[lb_size..lb_size + 91]).unwrap_err();
+}